@@ -25,4 +25,9 @@ class RubyStore
   def insert(key, val)
     rs_insert(key, val)
   end
+
+  # Adds this store to the ones RubyStore.serve_metrics(addr) exposes on /metrics, labelled with name.
+  def register_metrics(name)
+    rs_register_metrics(name.to_s)
+  end
 end
@@ -6,6 +6,7 @@ extern crate lazy_static;
 use ccache::errors::DecodeError;
 use ccache::errors::EncodeError;
 use ccache::in_memory_store::GetResult;
+use ccache::metrics::Registry;
 use ccache::serializable::Serializable;
 
 use derive::Serializable;
@@ -14,6 +15,12 @@ use rutie::rubysys::string;
 use rutie::types::{c_char, c_long};
 use rutie::{AnyObject, Class, NilClass, Object, RString, VM};
 use std::io::Write;
+use std::sync::Arc;
+
+lazy_static::lazy_static! {
+    // every store registered with RubyStore#register_metrics, served by RubyStore.serve_metrics
+    static ref METRICS: Arc<Registry> = Arc::new(Registry::new());
+}
 
 #[derive(Serializable, Debug)]
 #[encode_decode(lan = "ruby")]
@@ -27,7 +34,7 @@ impl Drop for RubyObject {
 }
 
 pub struct Store {
-    inner: ccache::in_memory_store::InMemoryStore<RubyObject>,
+    inner: Arc<ccache::in_memory_store::InMemoryStore<RubyObject>>,
     redis_client: redis::Connection,
 }
 
@@ -37,7 +44,7 @@ impl Store {
         let redic_connection = redis_client.get_connection()?;
 
         let store = Store {
-            inner: ccache::in_memory_store::InMemoryStore::new(),
+            inner: Arc::new(ccache::in_memory_store::InMemoryStore::new()),
             redis_client: redic_connection,
         };
 
@@ -101,7 +108,9 @@ methods!(
 
                 AnyObject::from(val.value)
             }
-            Ok(GetResult::Unchanged(val)) => AnyObject::from(val.value),
+            Ok(GetResult::Unchanged(val))
+            | Ok(GetResult::Unvalidated(val))
+            | Ok(GetResult::Stale(val, _)) => AnyObject::from(val.value),
             Ok(GetResult::None) => NilClass::new().into(),
             Err(error) => {
                 let error_class = Class::from_existing("CcacheRedisError");
@@ -109,6 +118,23 @@ methods!(
                 NilClass::new().into()
             }
         }
+    },
+    fn rs_register_metrics(name: RString) -> NilClass {
+        let store = rtself.get_data_mut(&*STORE_WRAPPER);
+        let inner = store.inner.clone();
+        METRICS.register(name.unwrap().to_str(), move || inner.stats());
+
+        NilClass::new()
+    },
+    fn rs_serve_metrics(addr: RString) -> AnyObject {
+        match METRICS.serve(addr.unwrap().to_str()) {
+            Ok(addr) => RString::new_utf8(&addr.to_string()).into(),
+            Err(error) => {
+                let error_class = Class::from_existing("StandardError");
+                VM::raise(error_class, &error.to_string());
+                NilClass::new().into()
+            }
+        }
     }
 );
 
@@ -119,6 +145,8 @@ pub extern "C" fn Init_ccache_rb() {
         klass.def_self("new", ruby_new);
         klass.def("rs_insert", ruby_insert);
         klass.def_private("rs_get", rs_get);
+        klass.def_private("rs_register_metrics", rs_register_metrics);
+        klass.def_self("serve_metrics", rs_serve_metrics);
     });
 }
 
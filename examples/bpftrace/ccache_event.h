@@ -0,0 +1,27 @@
+// Mirror of ccache::trace::Event, layout version 2. Keep in sync with src/trace.rs.
+#define CCACHE_EVENT_VERSION 2
+#define CCACHE_KEY_CAPACITY 128
+
+// values of ccache_event.result
+#define CCACHE_RESULT_UNKNOWN 0
+#define CCACHE_RESULT_UNCHANGED 1
+#define CCACHE_RESULT_UNVALIDATED 2
+#define CCACHE_RESULT_STALE 3
+#define CCACHE_RESULT_NEW 4
+#define CCACHE_RESULT_NONE 5
+#define CCACHE_RESULT_ERROR 6
+
+struct ccache_event {
+    unsigned short version;
+    unsigned char result;
+    unsigned char reserved;
+    unsigned int key_len;
+    unsigned long long monotonic_ns;
+    unsigned long long duration_ns;
+    unsigned long long bytes;
+    unsigned long long key_hash;
+    unsigned char trace_id[16];
+    char method[32];
+    char event[16];
+    char key[CCACHE_KEY_CAPACITY];
+};
@@ -1,7 +1,7 @@
 use bincode::{Decode, Encode};
 use ccache::in_memory_store::InMemoryStore;
 use ccache::serializable::Serializable;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use tide::Request;
 extern crate flate2;
 use ccache::errors::DecodeError;
@@ -21,16 +21,16 @@ struct World(Vec<Entity>);
 
 struct AppState<T: Serializable> {
     in_memory_store: Arc<InMemoryStore<T>>,
-    redis_conn: Arc<Mutex<redis::Connection>>,
+    redis_conn: redis::aio::MultiplexedConnection,
 }
 
 impl<T: Serializable> AppState<T> {
-    pub fn new() -> Self {
+    pub async fn new() -> Self {
         let client = redis::Client::open("redis://127.0.0.1/").unwrap();
-        let redis_conn = client.get_connection().unwrap();
+        let redis_conn = client.get_multiplexed_async_std_connection().await.unwrap();
         Self {
             in_memory_store: Arc::new(InMemoryStore::<T>::new()),
-            redis_conn: Arc::new(Mutex::new(redis_conn)),
+            redis_conn,
         }
     }
 }
@@ -38,7 +38,9 @@ impl<T: Serializable> AppState<T> {
 async fn handle_get(req: Request<Arc<AppState<World>>>) -> tide::Result {
     let state = req.state().clone();
     let store = state.in_memory_store.clone();
-    let rv = store.get("some-key", &mut state.redis_conn.clone().lock().unwrap());
+    let rv = store
+        .get_async("some-key", &mut state.redis_conn.clone())
+        .await;
     println!("w=#{:?}", rv);
 
     Ok(format!("Hello").into())
@@ -49,7 +51,8 @@ async fn handle_post(req: Request<Arc<AppState<World>>>) -> tide::Result {
     let store = state.in_memory_store.clone();
     let w = World(vec![Entity { x: 0.0, y: 4.0 }, Entity { x: 10.0, y: 20.5 }]);
     store
-        .insert("some-key", w, &mut state.redis_conn.clone().lock().unwrap())
+        .insert_async("some-key", w, &mut state.redis_conn.clone())
+        .await
         .unwrap();
 
     Ok(format!("Hello post").into())
@@ -57,7 +60,7 @@ async fn handle_post(req: Request<Arc<AppState<World>>>) -> tide::Result {
 
 #[async_std::main]
 async fn main() -> tide::Result<()> {
-    let app_state: Arc<AppState<World>> = Arc::new(AppState::new());
+    let app_state: Arc<AppState<World>> = Arc::new(AppState::new().await);
     let mut app = tide::with_state(app_state);
 
     app.at("/").get(handle_get);
@@ -6,11 +6,20 @@ bpf_program = """
 #include <uapi/linux/ptrace.h>
 #include <linux/sched.h>
 
+// ccache::trace::Event, layout version 2
 struct data_t {
+    u16 version;
+    u8 result;
+    u8 reserved;
+    u32 key_len;
+    u64 monotonic_ns;
+    u64 duration_ns;
+    u64 bytes;
+    u64 key_hash;
+    u8 trace_id[16];
     char method[32];
     char event[16];
-    char key[32];
-    char trace_id[32];
+    char key[128];
 };
 
 BPF_PERF_OUTPUT(events);
@@ -42,21 +51,39 @@ usdt.enable_probe(probe="store", fn_name="probe")
 b = BPF(text=bpf_program, usdt_contexts=[usdt])
 
 # Define output data structure in Python
+EVENT_VERSION = 2
+RESULTS = ["unknown", "unchanged", "unvalidated", "stale", "new", "none", "error"]
+
 class Data(ct.Structure):
     _fields_ = [
+        ("version", ct.c_uint16),
+        ("result", ct.c_uint8),
+        ("reserved", ct.c_uint8),
+        ("key_len", ct.c_uint32),
+        ("monotonic_ns", ct.c_uint64),
+        ("duration_ns", ct.c_uint64),
+        ("bytes", ct.c_uint64),
+        ("key_hash", ct.c_uint64),
+        ("trace_id", ct.c_uint8 * 16),
         ("method", ct.c_char * 32),
         ("event", ct.c_char * 16),
-        ("key", ct.c_char * 32),
-        ("trace_id", ct.c_char * 32),
+        ("key", ct.c_char * 128),
     ]
 
 # Callback to handle events
 def print_event(cpu, data, size):
     event = ct.cast(data, ct.POINTER(Data)).contents
+    if event.version != EVENT_VERSION:
+        print(f"unknown event version {event.version}\n")
+        return
+
     print(f"method: {event.method.decode('utf-8', 'replace')}")
     print(f"event: {event.event.decode('utf-8', 'replace')}")
-    print(f"key: {event.key.decode('utf-8', 'replace')}")
-    print(f"trace_id: {event.trace_id.decode('utf-8', 'replace')}\n")
+    print(f"key: {event.key.decode('utf-8', 'replace')} ({event.key_len} bytes, hash {event.key_hash:x})")
+    print(f"result: {RESULTS[event.result] if event.result < len(RESULTS) else event.result}")
+    print(f"bytes: {event.bytes}")
+    print(f"duration_ns: {event.duration_ns}")
+    print(f"trace_id: {bytes(event.trace_id).hex()}\n")
 
 # Open perf buffer
 b["events"].open_perf_buffer(print_event)
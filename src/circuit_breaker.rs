@@ -0,0 +1,218 @@
+use crate::errors::Error;
+use crate::trace;
+
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use probe::probe;
+
+const CLOSED: u8 = 0;
+const OPEN: u8 = 1;
+const HALF_OPEN: u8 = 2;
+
+// A request let through, its outcome goes to record. It's a guard: a probe dropped without being recorded,
+// e.g. as its future was dropped or it panicked, opens the breaker again rather than leaving it half open.
+pub(crate) struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    // the single request probing Redis after the breaker was open for a while
+    probe: bool,
+    recorded: bool,
+}
+
+impl Permit<'_> {
+    #[cfg(test)]
+    pub fn is_probe(&self) -> bool {
+        self.probe
+    }
+
+    // Only Redis being unavailable counts as a failure, any other error means Redis did answer.
+    pub fn record<R>(mut self, result: &Result<R, Error>) {
+        self.recorded = true;
+        let failed = matches!(result, Err(e) if e.is_unavailable());
+
+        match (self.probe, failed) {
+            (true, false) => {
+                self.breaker.failures.store(0, Ordering::Relaxed);
+                self.breaker.state.store(CLOSED, Ordering::Release);
+                emit("closed");
+            }
+            (true, true) => self.breaker.open(),
+            (false, false) => {
+                // avoid writing the cache line when there is nothing to reset
+                if self.breaker.failures.load(Ordering::Relaxed) > 0 {
+                    self.breaker.failures.store(0, Ordering::Relaxed);
+                }
+            }
+            (false, true) => {
+                let failures = self.breaker.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures >= self.breaker.failure_threshold
+                    && self.breaker.state.load(Ordering::Acquire) == CLOSED
+                {
+                    self.breaker.open();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.probe && !self.recorded {
+            self.breaker.open();
+        }
+    }
+}
+
+// Guards validation requests to Redis. After failure_threshold consecutive failures it opens,
+// and requests fail fast with Error::CircuitOpen. Once open_for has passed, one request is let through
+// as a probe, its outcome closes the breaker or opens it again.
+// The hot path only reads atomics, the mutex serializes the rare state changes.
+pub(crate) struct CircuitBreaker {
+    failure_threshold: u32,
+    open_for: Duration,
+    state: AtomicU8,
+    failures: AtomicU32,
+    opened_at: Mutex<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_for: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            open_for,
+            state: AtomicU8::new(CLOSED),
+            failures: AtomicU32::new(0),
+            opened_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    // None while requests should fail fast.
+    pub fn acquire(&self) -> Option<Permit<'_>> {
+        match self.state.load(Ordering::Acquire) {
+            CLOSED => Some(self.permit(false)),
+            OPEN => {
+                let opened_at = self.opened_at.lock().unwrap();
+                if opened_at.elapsed() < self.open_for {
+                    return None;
+                }
+
+                // only the request which flips the state probes
+                self.state
+                    .compare_exchange(OPEN, HALF_OPEN, Ordering::AcqRel, Ordering::Acquire)
+                    .ok()?;
+                drop(opened_at);
+                emit("half_open");
+
+                Some(self.permit(true))
+            }
+            _ => None,
+        }
+    }
+
+    fn permit(&self, probe: bool) -> Permit<'_> {
+        Permit {
+            breaker: self,
+            probe,
+            recorded: false,
+        }
+    }
+
+    fn open(&self) {
+        let mut opened_at = self.opened_at.lock().unwrap();
+        if self.state.load(Ordering::Acquire) == OPEN {
+            return;
+        }
+
+        *opened_at = Instant::now();
+        self.state.store(OPEN, Ordering::Release);
+        drop(opened_at);
+
+        emit("open");
+    }
+}
+
+fn emit(event: &str) {
+    probe!(
+        ccache,
+        breaker,
+        trace::Event::new("circuit_breaker", event, "").as_ptr()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure() -> Result<(), Error> {
+        Err(Error::from(redis::RedisError::from(std::io::Error::from(
+            std::io::ErrorKind::ConnectionRefused,
+        ))))
+    }
+
+    #[test]
+    fn test_open_and_close() {
+        let breaker = CircuitBreaker::new(3, Duration::from_millis(20));
+
+        for _ in 0..2 {
+            breaker.acquire().unwrap().record(&failure());
+        }
+        // a success resets the count
+        breaker.acquire().unwrap().record(&Ok(()));
+        for _ in 0..2 {
+            breaker.acquire().unwrap().record(&failure());
+        }
+        assert!(breaker.acquire().is_some());
+
+        breaker.acquire().unwrap().record(&failure());
+        assert!(breaker.acquire().is_none());
+
+        std::thread::sleep(Duration::from_millis(30));
+        let probe = breaker.acquire().unwrap();
+        assert!(probe.is_probe());
+        // a single probe at a time
+        assert!(breaker.acquire().is_none());
+
+        // the probe failed, so wait again
+        probe.record(&failure());
+        assert!(breaker.acquire().is_none());
+
+        std::thread::sleep(Duration::from_millis(30));
+        breaker.acquire().unwrap().record(&Ok(()));
+        assert!(breaker.acquire().is_some_and(|permit| !permit.is_probe()));
+    }
+
+    #[test]
+    fn test_only_unavailable_counts() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(20));
+
+        // Redis answered, with an error
+        let answered: Result<(), Error> = Err(Error::from(redis::RedisError::from((
+            redis::ErrorKind::ResponseError,
+            "WRONGTYPE",
+        ))));
+        breaker.acquire().unwrap().record(&answered);
+        breaker
+            .acquire()
+            .unwrap()
+            .record::<()>(&Err(Error::Protocol("unexpected".to_string())));
+        assert!(breaker.acquire().is_some());
+
+        breaker.acquire().unwrap().record(&failure());
+        assert!(breaker.acquire().is_none());
+    }
+
+    #[test]
+    fn test_dropped_probe_opens_again() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(20));
+        breaker.acquire().unwrap().record(&failure());
+
+        std::thread::sleep(Duration::from_millis(30));
+        drop(breaker.acquire().unwrap());
+        assert!(breaker.acquire().is_none());
+
+        // and lets another probe through once open_for passed again
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.acquire().unwrap().is_probe());
+    }
+}
@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 #[derive(Debug)]
 pub enum EncodeError {
     Bincode(bincode::error::EncodeError),
@@ -51,3 +53,127 @@ impl From<std::io::Error> for DecodeError {
         DecodeError::Flate2(error)
     }
 }
+
+impl std::error::Error for EncodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            EncodeError::Bincode(ref err) => Some(err),
+            EncodeError::Flate2(ref err) => Some(err),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            DecodeError::Bincode(ref err) => Some(err),
+            DecodeError::Flate2(ref err) => Some(err),
+        }
+    }
+}
+
+// The error of every fallible InMemoryStore operation.
+// It's Clone, as followers of a coalesced request get the leader's error, so sources are behind an Arc.
+#[derive(Clone, Debug)]
+pub enum Error {
+    Redis(Arc<redis::RedisError>),
+    Encode(Arc<dyn std::error::Error + Send + Sync>),
+    // e.g. a corrupt payload in Redis
+    Decode(Arc<dyn std::error::Error + Send + Sync>),
+    // the loader of get_or_load failed
+    Load(Arc<dyn std::error::Error + Send + Sync>),
+    // Redis replied something ccache doesn't expect
+    Protocol(String),
+    // waited too long for another process, e.g. get_or_load for the load of another process to finish
+    Timeout(String),
+    // validation was skipped, as the circuit breaker is open after Redis kept failing
+    CircuitOpen,
+    // the leader of a coalesced request was dropped before publishing its result, as it panicked
+    // or its future was dropped. Its followers take over the request rather than return this.
+    Aborted,
+    // insert_if_match found another version, current_etag is its etag, None if the key doesn't exist
+    Conflict { current_etag: Option<Vec<u8>> },
+    // with_tracking couldn't spawn the thread listening for invalidations
+    Spawn(Arc<std::io::Error>),
+}
+
+impl Error {
+    pub fn encode<E: std::error::Error + Send + Sync + 'static>(error: E) -> Self {
+        Error::Encode(Arc::new(error))
+    }
+
+    pub fn decode<E: std::error::Error + Send + Sync + 'static>(error: E) -> Self {
+        Error::Decode(Arc::new(error))
+    }
+
+    pub fn load<E: std::error::Error + Send + Sync + 'static>(error: E) -> Self {
+        Error::Load(Arc::new(error))
+    }
+
+    // Whether Redis couldn't be reached or didn't answer in time, as opposed to answering with an error.
+    // Only these fail a validation over to a stale copy, and count against the circuit breaker.
+    pub fn is_unavailable(&self) -> bool {
+        match *self {
+            Error::Redis(ref err) => {
+                err.is_io_error()
+                    || err.is_timeout()
+                    || err.is_connection_dropped()
+                    || err.is_connection_refusal()
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        match *self {
+            Error::Timeout(_) => true,
+            Error::Redis(ref err) => err.is_timeout(),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Error::Redis(ref err) => write!(f, "Redis error: {}", err),
+            Error::Encode(ref err) => write!(f, "Encode error: {}", err),
+            Error::Decode(ref err) => write!(f, "Decode error: {}", err),
+            Error::Load(ref err) => write!(f, "Load error: {}", err),
+            Error::Protocol(ref description) => write!(f, "Protocol error: {}", description),
+            Error::Timeout(ref description) => write!(f, "Timeout: {}", description),
+            Error::CircuitOpen => write!(f, "Circuit breaker is open"),
+            Error::Aborted => write!(f, "Request aborted by its leader"),
+            Error::Conflict {
+                current_etag: Some(ref etag),
+            } => write!(
+                f,
+                "etag mismatch, current etag is {}",
+                String::from_utf8_lossy(etag)
+            ),
+            Error::Conflict { current_etag: None } => {
+                write!(f, "etag mismatch, key does not exist")
+            }
+            Error::Spawn(ref err) => write!(f, "Spawn error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::Redis(ref err) => Some(&**err),
+            Error::Encode(ref err) => Some(&**err),
+            Error::Decode(ref err) => Some(&**err),
+            Error::Load(ref err) => Some(&**err),
+            Error::Spawn(ref err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
+
+impl From<redis::RedisError> for Error {
+    fn from(error: redis::RedisError) -> Self {
+        Error::Redis(Arc::new(error))
+    }
+}
@@ -1,59 +1,57 @@
+use crate::circuit_breaker::{CircuitBreaker, Permit};
+use crate::errors::Error;
+use crate::local_cache::{version_of, DataInner, LocalCache};
 use crate::partitioned_hash_map::PartitionedHashMap;
 use crate::serializable::Serializable;
-use crate::trace;
-
-use std::collections::hash_map::RandomState;
-use std::collections::HashMap;
-use std::fmt;
-use std::sync::{Arc, Condvar, Mutex};
-
+use crate::stats::{self, Counters, Stats};
+use crate::trace::{self, trace_event, EventResult, TraceId};
+use crate::tracking::Tracker;
+
+use std::cell::Cell;
+use std::collections::hash_map::{Entry, RandomState};
+use std::hash::{Hash, Hasher};
+use std::io::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use event_listener::Event;
 use likely_stable::{likely, unlikely};
-use probe::probe;
+use once_cell::sync::Lazy;
+use probe::probe_lazy;
 use redis::Script;
-use uuid::Uuid;
-
-#[derive(Clone, Debug)]
-pub struct CcacheRedisError {
-    description: String,
-}
-
-impl fmt::Display for CcacheRedisError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.description)
-    }
-}
-
-impl From<redis::RedisError> for CcacheRedisError {
-    fn from(e: redis::RedisError) -> Self {
-        CcacheRedisError {
-            description: e.to_string(),
-        }
-    }
-}
-
-struct DataInner<T>(Vec<u8>, Arc<T>);
 
-impl<T> DataInner<T> {
-    pub fn val(&self) -> Arc<T> {
-        self.1.clone()
-    }
+pub use crate::local_cache::Capacity;
 
-    pub fn etag(&self) -> &Vec<u8> {
-        &self.0
-    }
-}
+const DEFAULT_MAX_UPDATE_RETRIES: usize = 16;
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_LOAD_LOCK: Duration = Duration::from_secs(10);
+// how often get_or_load checks for the value another process is loading, backing off up to the max
+const LOAD_POLL_INTERVAL: Duration = Duration::from_millis(20);
+const MAX_LOAD_POLL_INTERVAL: Duration = Duration::from_millis(200);
 
 pub struct InMemoryStore<T: Serializable> {
     coder_config: T::Config,
-    map: PartitionedHashMap<String, Arc<DataInner<T>>, RandomState>,
-    request_condvar:
-        PartitionedHashMap<(String, String), Arc<(Mutex<RedisMessage<T>>, Condvar)>, RandomState>,
+    map: LocalCache<T>,
+    max_update_retries: usize,
+    read_lease: Duration,
+    stale_if_error: Option<Duration>,
+    breaker: Option<CircuitBreaker>,
+    tracker: Option<Arc<Tracker>>,
+    wait_timeout: Duration,
+    load_lock: Duration,
+    request_condvar: PartitionedHashMap<RequestKey<T>, Pending<T>, RandomState>,
+    // id of the next leader, see Pending
+    next_leader: AtomicU64,
+    counters: Counters,
 }
 
 enum RequestThroughLocalResult {
     None,
-    Unchanged,
-    New(Vec<u8>, Vec<u8>),
+    // the lease deadline if one was granted
+    Unchanged(Option<Instant>),
+    // val, etag, the deadline of the Redis copy and the lease deadline
+    New(Vec<u8>, Vec<u8>, Option<Instant>, Option<Instant>),
 }
 
 #[derive(Debug)]
@@ -61,24 +59,77 @@ pub enum GetResult<T> {
     None,
     Unchanged(T),
     New(T),
+    // the local copy, served without asking Redis, see ReadOptions
+    Unvalidated(T),
+    // the local copy, as Redis couldn't be reached, see InMemoryStore::with_stale_if_error
+    Stale(T, Error),
+}
+
+// Options of get_with_options.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadOptions {
+    // A local copy which Redis confirmed within max_staleness is returned as is, as GetResult::Unvalidated.
+    // Zero always validates, like get.
+    pub max_staleness: Duration,
 }
 
 impl<T> GetResult<T> {
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> GetResult<U> {
+        match self {
+            GetResult::None => GetResult::None,
+            GetResult::Unchanged(val) => GetResult::Unchanged(f(val)),
+            GetResult::New(val) => GetResult::New(f(val)),
+            GetResult::Unvalidated(val) => GetResult::Unvalidated(f(val)),
+            GetResult::Stale(val, e) => GetResult::Stale(f(val), e),
+        }
+    }
+
     pub fn unwrap(self) -> T {
         match self {
             GetResult::Unchanged(val) => val,
             GetResult::New(val) => val,
+            GetResult::Unvalidated(val) => val,
+            GetResult::Stale(val, _) => val,
             GetResult::None => panic!("called `GetResult::unwrap()` on a `None` value"),
         }
     }
 }
 
-#[derive(Debug)]
+// a value with its etag
+pub type Versioned<T> = (Arc<T>, Vec<u8>);
+
+// get result carrying the local entry, so its etag stays paired with the value
+type EntryResult<T> = Result<GetResult<Arc<DataInner<T>>>, Error>;
+
 enum RedisResult<T> {
     None,
     Unchanged,
-    New(Arc<T>),
-    Error(CcacheRedisError),
+    New(Arc<DataInner<T>>),
+    Error(Error),
+}
+
+impl<T: Serializable> Drop for InMemoryStore<T> {
+    fn drop(&mut self) {
+        if let Some(tracker) = &self.tracker {
+            tracker.stop();
+        }
+    }
+}
+
+// Reply of a write script.
+enum WriteReply<R> {
+    Done(R),
+    // readers hold leases on a key, retry after this long
+    Wait(Duration),
+}
+
+impl<R> WriteReply<R> {
+    fn map<U, F: FnOnce(R) -> U>(self, f: F) -> WriteReply<U> {
+        match self {
+            WriteReply::Done(reply) => WriteReply::Done(f(reply)),
+            WriteReply::Wait(wait) => WriteReply::Wait(wait),
+        }
+    }
 }
 
 struct RedisMessage<T> {
@@ -95,539 +146,3817 @@ impl<T> RedisMessage<T> {
     }
 }
 
-const GET_FROM_REDIS_SCRIPT: &str = r#"
+// Where the waiters of an in-flight etag request park. Blocking waiters park on `cvar`,
+// async waiters listen on `event`, both are woken when the result is published.
+struct RequestSlot<T> {
+    message: Mutex<RedisMessage<T>>,
+    cvar: Condvar,
+    event: Event,
+}
+
+impl<T> RequestSlot<T> {
+    fn new() -> Self {
+        RequestSlot {
+            message: Mutex::new(RedisMessage::new()),
+            cvar: Condvar::new(),
+            event: Event::new(),
+        }
+    }
+}
+
+// What a request validates. A local copy is identified by its entry, compared by address,
+// so joining the request of a local hit doesn't allocate. A local miss always sends ETAG_UNCHANGED.
+enum RequestKey<T> {
+    Hit(Arc<DataInner<T>>),
+    Miss(String),
+    // get_or_load loading the key
+    Load(String),
+}
+
+impl<T> RequestKey<T> {
+    fn of(key: &str, entry: &Option<Arc<DataInner<T>>>) -> Self {
+        match entry {
+            Some(entry) => RequestKey::Hit(entry.clone()),
+            None => RequestKey::Miss(key.to_string()),
+        }
+    }
+}
+
+impl<T> Clone for RequestKey<T> {
+    fn clone(&self) -> Self {
+        match self {
+            RequestKey::Hit(entry) => RequestKey::Hit(entry.clone()),
+            RequestKey::Miss(key) => RequestKey::Miss(key.clone()),
+            RequestKey::Load(key) => RequestKey::Load(key.clone()),
+        }
+    }
+}
+
+impl<T> PartialEq for RequestKey<T> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (RequestKey::Hit(a), RequestKey::Hit(b)) => Arc::ptr_eq(a, b),
+            (RequestKey::Miss(a), RequestKey::Miss(b)) => a == b,
+            (RequestKey::Load(a), RequestKey::Load(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl<T> Eq for RequestKey<T> {}
+
+impl<T> Hash for RequestKey<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            RequestKey::Hit(entry) => Arc::as_ptr(entry).hash(state),
+            RequestKey::Miss(key) | RequestKey::Load(key) => key.hash(state),
+        }
+    }
+}
+
+// An in-flight request. Its slot is built by the first waiter, an uncontended request never allocates one.
+struct Pending<T> {
+    // id of the leader doing the request
+    leader: u64,
+    slot: Option<Arc<RequestSlot<T>>>,
+    // leaders which were taken over, and the slots of their waiters
+    orphans: Vec<(u64, Arc<RequestSlot<T>>)>,
+}
+
+impl<T> Pending<T> {
+    fn new(leader: u64) -> Self {
+        Pending {
+            leader,
+            slot: None,
+            orphans: Vec::new(),
+        }
+    }
+
+    fn slot(&mut self) -> Arc<RequestSlot<T>> {
+        self.slot
+            .get_or_insert_with(|| Arc::new(RequestSlot::new()))
+            .clone()
+    }
+}
+
+enum Flight<'a, T: Serializable> {
+    Leader(Leader<'a, T>),
+    Follower(Arc<RequestSlot<T>>),
+}
+
+// The request side of a Pending. Dropped without finish, e.g. the leader panicked or its future was dropped,
+// it publishes Error::Aborted, so the request is removed and its waiters don't wait for nothing.
+struct Leader<'a, T: Serializable> {
+    store: &'a InMemoryStore<T>,
+    request_key: RequestKey<T>,
+    id: u64,
+    finished: bool,
+}
+
+impl<'a, T: Serializable> Leader<'a, T> {
+    fn new(store: &'a InMemoryStore<T>, request_key: RequestKey<T>, id: u64) -> Self {
+        Leader {
+            store,
+            request_key,
+            id,
+            finished: false,
+        }
+    }
+
+    fn finish(mut self, redis_result: RedisResult<T>) {
+        self.finished = true;
+        self.store.publish(&self.request_key, self.id, redis_result);
+    }
+}
+
+impl<T: Serializable> Drop for Leader<'_, T> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.store.publish(
+                &self.request_key,
+                self.id,
+                RedisResult::Error(Error::Aborted),
+            );
+        }
+    }
+}
+
+// A read lease is a promise from Redis that a key won't change before the lease expires,
+// so its holder can serve the local copy without revalidating. The field "lease" of the key's lease hash
+// (see lease_key) is the latest deadline granted, in Redis TIME milliseconds. A writer waits for
+// the deadline, and meanwhile sets "fence" to stop new grants, otherwise a read-heavy key could starve it.
+// The lease hash expires with its last deadline. It's kept off the key itself, so granting a lease
+// isn't a write of the key, which would invalidate it for with_tracking.
+// Scripts get the lease hash in KEYS after the keys, only from stores using leases. Without it
+// no lease is granted or waited for.
+macro_rules! lease_lua {
+    () => {
+        r#"
+  local function now_ms()
+     local time = redis.call('TIME')
+     return tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+  end
+
+  -- returns the lease granted through lease_key in milliseconds, 0 for none
+  local function grant_lease(lease_key, ms)
+     if not lease_key or ms <= 0 then
+        return 0
+     end
+     local now = now_ms()
+     local fence = tonumber(redis.call("HGET", lease_key, "fence"))
+     if fence and fence > now then
+        return 0
+     end
+     local lease = tonumber(redis.call("HGET", lease_key, "lease"))
+     if not lease or lease < now + ms then
+        local deadline = string.format("%.0f", now + ms)
+        redis.call("HSET", lease_key, "lease", deadline)
+        redis.call("PEXPIREAT", lease_key, deadline)
+     end
+     return ms
+  end
+
+  -- returns how many milliseconds a writer has to wait for the leases of lease_key, 0 if it can write now
+  local function lease_wait(lease_key)
+     if not lease_key then
+        return 0
+     end
+     local lease = tonumber(redis.call("HGET", lease_key, "lease"))
+     if not lease then
+        return 0
+     end
+     local wait = lease - now_ms()
+     if wait > 0 then
+        local fence = string.format("%.0f", lease + math.max(wait, 100))
+        redis.call("HSET", lease_key, "fence", fence)
+        redis.call("PEXPIREAT", lease_key, fence)
+        return wait
+     end
+     redis.call("DEL", lease_key)
+     return 0
+  end
+"#
+    };
+}
+
+// ARGV[2] is the lease to ask for in milliseconds, 0 for none, KEYS[2] the lease hash if it isn't
+const GET_FROM_REDIS_SCRIPT: &str = concat!(
+    lease_lua!(),
+    r#"
 if (redis.call("HGET", KEYS[1], "etag") == ARGV[1]) then
-   return {"etag","-1"}
+   return {"etag", "-1", "lease_ms", tostring(grant_lease(KEYS[2], tonumber(ARGV[2])))}
 else
-   return redis.call("HGETALL", KEYS[1])
+   local rv = redis.call("HGETALL", KEYS[1])
+   if #rv > 0 then
+      rv[#rv + 1] = "pttl"
+      rv[#rv + 1] = tostring(redis.call("PTTL", KEYS[1]))
+      rv[#rv + 1] = "lease_ms"
+      rv[#rv + 1] = tostring(grant_lease(KEYS[2], tonumber(ARGV[2])))
+   end
+   return rv
 end
-"#;
+"#
+);
+
+// built once, Script::new hashes the source
+static GET_FROM_REDIS: Lazy<Script> = Lazy::new(|| Script::new(GET_FROM_REDIS_SCRIPT));
 
-const INSERT_TO_REDIS_SCRIPT: &str = r#"
-  local time = redis.call('TIME')[1]
-  redis.call("HSET", KEYS[1], "val", ARGV[1], "etag", time)
+thread_local! {
+    // the packed EVALSHA of GET_FROM_REDIS, reused so a get doesn't allocate one, see pack_get_command
+    static GET_COMMAND: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
+}
+
+// GET_FROM_REDIS_SCRIPT for every key, ARGV[i] is the etag of KEYS[i], the last ARGV is the lease.
+// With a lease, KEYS[n + i] is the lease hash of KEYS[i].
+const GET_MANY_FROM_REDIS_SCRIPT: &str = concat!(
+    lease_lua!(),
+    r#"
+local n = #ARGV - 1
+local lease_ms = tonumber(ARGV[n + 1])
+local rv = {}
+for i = 1, n do
+   local key = KEYS[i]
+   if (redis.call("HGET", key, "etag") == ARGV[i]) then
+      rv[i] = {"etag", "-1", "lease_ms", tostring(grant_lease(KEYS[n + i], lease_ms))}
+   else
+      local all = redis.call("HGETALL", key)
+      if #all > 0 then
+         all[#all + 1] = "pttl"
+         all[#all + 1] = tostring(redis.call("PTTL", key))
+         all[#all + 1] = "lease_ms"
+         all[#all + 1] = tostring(grant_lease(KEYS[n + i], lease_ms))
+      end
+      rv[i] = all
+   end
+end
+return rv
+"#
+);
+
+// An etag is the version of a key, Redis TIME in microseconds, bumped past the previous etag of the key
+// when the clock didn't move (or moved back). So etags of a key strictly increase, and as the clock
+// keeps going, a key deleted and inserted again doesn't reuse an old etag either.
+// Microseconds fit the 53 bits integer precision of Lua numbers.
+macro_rules! next_etag_lua {
+    () => {
+        r#"
+  local function next_etag(key)
+     local time = redis.call('TIME')
+     local etag = tonumber(time[1]) * 1000000 + tonumber(time[2])
+     local prev = tonumber(redis.call("HGET", key, "etag"))
+     if prev and prev >= etag then
+        etag = prev + 1
+     end
+     return string.format("%.0f", etag)
+  end
+"#
+    };
+}
 
-  return time
+// Write scripts return {wait, reply}, wait is the milliseconds to wait for read leases before retrying,
+// the reply is only meaningful when wait is 0. KEYS[2] is the lease hash of KEYS[1], if the store uses leases.
+
+// ARGV[2] is the ttl in milliseconds, 0 for no expiry
+const INSERT_TO_REDIS_SCRIPT: &str = concat!(
+    lease_lua!(),
+    next_etag_lua!(),
+    r#"
+  local wait = lease_wait(KEYS[2])
+  if wait > 0 then
+     return {wait, false}
+  end
+
+  local etag = next_etag(KEYS[1])
+  redis.call("HSET", KEYS[1], "val", ARGV[1], "etag", etag)
+  if tonumber(ARGV[2]) > 0 then
+     redis.call("PEXPIRE", KEYS[1], ARGV[2])
+  else
+     redis.call("PERSIST", KEYS[1])
+  end
+
+  return {0, etag}
+"#
+);
+
+// INSERT_TO_REDIS_SCRIPT without expiry for every key, ARGV[i] is the value of KEYS[i],
+// KEYS[n + i] its lease hash if the store uses leases
+const INSERT_MANY_TO_REDIS_SCRIPT: &str = concat!(
+    lease_lua!(),
+    next_etag_lua!(),
+    r#"
+  local n = #ARGV
+  local wait = 0
+  for i = 1, n do
+     wait = math.max(wait, lease_wait(KEYS[n + i]))
+  end
+  if wait > 0 then
+     return {wait, false}
+  end
+
+  local rv = {}
+  for i = 1, n do
+     local key = KEYS[i]
+     local etag = next_etag(key)
+     redis.call("HSET", key, "val", ARGV[i], "etag", etag)
+     redis.call("PERSIST", key)
+     rv[i] = etag
+  end
+
+  return {0, rv}
+"#
+);
+
+// INSERT_TO_REDIS_SCRIPT keeping the expiry the key has, only if the etag of KEYS[1] is ARGV[2]
+// ("" for a missing key). Replies {1, new etag, PTTL} or {0, current etag, -1}.
+const INSERT_IF_MATCH_SCRIPT: &str = concat!(
+    lease_lua!(),
+    next_etag_lua!(),
+    r#"
+  local current = redis.call("HGET", KEYS[1], "etag")
+  if (current or "") ~= ARGV[2] then
+     return {0, {0, current, -1}}
+  end
+
+  local wait = lease_wait(KEYS[2])
+  if wait > 0 then
+     return {wait, false}
+  end
+
+  local etag = next_etag(KEYS[1])
+  redis.call("HSET", KEYS[1], "val", ARGV[1], "etag", etag)
+
+  return {0, {1, etag, redis.call("PTTL", KEYS[1])}}
+"#
+);
+
+const REMOVE_FROM_REDIS_SCRIPT: &str = concat!(
+    lease_lua!(),
+    r#"
+  local wait = lease_wait(KEYS[2])
+  if wait > 0 then
+     return {wait, false}
+  end
+
+  local etag = redis.call("HGET", KEYS[1], "etag")
+  redis.call("DEL", KEYS[1])
+
+  return {0, etag}
+"#
+);
+
+// KEYS[1] is the lock key, ARGV[1] the token it was taken with
+const UNLOCK_SCRIPT: &str = r#"
+  if redis.call("GET", KEYS[1]) == ARGV[1] then
+     return redis.call("DEL", KEYS[1])
+  end
+  return 0
 "#;
 
 const ETAG_UNCHANGED: &[u8] = "-1".as_bytes();
 
+const LEASE_KEY_PREFIX: &str = "ccache:lease:";
+
+// Parts of the key of key's lease hash, see lease_lua. It has to be in the cluster slot of key,
+// so a key with a hash tag keeps it, and any other key becomes the hash tag as a whole.
+// A key without a hash tag containing a '}' can't be, its lease hash ends up in another slot.
+fn lease_key_parts(key: &str) -> [&str; 4] {
+    let has_hash_tag = key
+        .find('{')
+        .is_some_and(|open| key[open + 1..].find('}').is_some_and(|close| close > 0));
+
+    if has_hash_tag {
+        [LEASE_KEY_PREFIX, "", key, ""]
+    } else {
+        [LEASE_KEY_PREFIX, "{", key, "}"]
+    }
+}
+
+fn lease_key(key: &str) -> String {
+    lease_key_parts(key).concat()
+}
+
 impl<T: Serializable> InMemoryStore<T> {
     pub fn new() -> Self {
         Self {
             coder_config: T::config(),
-            map: PartitionedHashMap::new(),
+            map: LocalCache::new(Capacity::Unbounded),
+            max_update_retries: DEFAULT_MAX_UPDATE_RETRIES,
+            read_lease: Duration::ZERO,
+            stale_if_error: None,
+            breaker: None,
+            tracker: None,
+            wait_timeout: DEFAULT_WAIT_TIMEOUT,
+            load_lock: DEFAULT_LOAD_LOCK,
             request_condvar: PartitionedHashMap::new(),
+            next_leader: AtomicU64::new(0),
+            counters: Counters::default(),
+        }
+    }
+
+    // Asks Redis for a read lease of this length on every validated read. While the lease lasts, get serves
+    // the local copy as GetResult::Unchanged without a round trip, and writers wait for it before the new value
+    // becomes visible. So a long lease delays writes of read-heavy keys. Only writes of stores with a read lease
+    // (of any length) wait, so every store writing the keys has to be set up with one, the others skip the
+    // lease bookkeeping altogether.
+    pub fn with_read_lease(mut self, lease: Duration) -> Self {
+        self.read_lease = lease;
+        self
+    }
+
+    // Subscribes to Redis 6 client side caching invalidations of the keys under prefixes through client.
+    // While the subscription is healthy, an entry no write touched since its last validation is served
+    // as GetResult::Unchanged without a round trip. Otherwise, and for keys outside prefixes, get falls back
+    // to the etag check. Redis publishes every write under prefixes, so keep them to the keys of this store.
+    // Fails with Error::Spawn if the thread listening for invalidations can't be spawned.
+    pub fn with_tracking(
+        mut self,
+        client: redis::Client,
+        prefixes: &[&str],
+    ) -> Result<Self, Error> {
+        let tracker =
+            Tracker::start(client, prefixes).map_err(|err| Error::Spawn(Arc::new(err)))?;
+        self.tracker = Some(tracker);
+        Ok(self)
+    }
+
+    // When validating against Redis fails as Redis can't be reached or doesn't answer in time (see
+    // Error::is_unavailable), or the circuit breaker is open, get returns the local copy as GetResult::Stale
+    // along with the error, if Redis confirmed the copy within max_age. Errors Redis answered with are returned.
+    pub fn with_stale_if_error(mut self, max_age: Duration) -> Self {
+        self.stale_if_error = Some(max_age);
+        self
+    }
+
+    // After failures consecutive validations of gets which couldn't reach Redis (see Error::is_unavailable),
+    // fails them fast with Error::CircuitOpen (or serves them stale, see with_stale_if_error) for open_for,
+    // then lets a single get probe Redis.
+    pub fn with_circuit_breaker(mut self, failures: u32, open_for: Duration) -> Self {
+        self.breaker = Some(CircuitBreaker::new(failures, open_for));
+        self
+    }
+
+    // A snapshot of the counters of this store, cheap enough to poll.
+    pub fn stats(&self) -> Stats {
+        let (entries, entry_bytes) = self.map.entries();
+
+        Stats {
+            entries,
+            entry_bytes,
+            ..self.counters.snapshot()
         }
     }
 
-    pub fn insert(
+    // Whether with_tracking is on and its subscription is healthy.
+    pub fn is_tracking(&self) -> bool {
+        self.tracker
+            .as_ref()
+            .is_some_and(|tracker| tracker.is_healthy())
+    }
+
+    // How long a get waits for the request of another get of the same key and etag,
+    // afterwards it stops waiting and does the request itself.
+    pub fn with_wait_timeout(mut self, timeout: Duration) -> Self {
+        self.wait_timeout = timeout;
+        self
+    }
+
+    // How long get_or_load holds the lock key while its loader runs. A loader running longer
+    // loses the lock, and another process may load the same key concurrently.
+    pub fn with_load_lock(mut self, ttl: Duration) -> Self {
+        self.load_lock = ttl;
+        self
+    }
+
+    // How many times update retries after losing a race to another writer.
+    pub fn with_max_update_retries(mut self, retries: usize) -> Self {
+        self.max_update_retries = retries;
+        self
+    }
+
+    // Bounds the local map, entries are evicted per shard with CLOCK (an approximated LRU).
+    pub fn with_capacity(mut self, capacity: Capacity) -> Self {
+        self.map = LocalCache::new(capacity);
+        self
+    }
+
+    pub fn insert<C: redis::ConnectionLike>(
+        &self,
+        key: &str,
+        val: T,
+        redis_conn: &mut C,
+    ) -> Result<Vec<u8>, Error> {
+        self.insert_with_expiry(key, val, None, redis_conn)
+    }
+
+    // Like insert, the key expires in Redis after ttl, and so does the local copy in every process.
+    pub fn insert_with_ttl<C: redis::ConnectionLike>(
         &self,
         key: &str,
         val: T,
-        redis_conn: &mut redis::Connection,
-    ) -> Result<Vec<u8>, redis::RedisError> {
-        let uuid = Uuid::new_v4();
+        ttl: Duration,
+        redis_conn: &mut C,
+    ) -> Result<Vec<u8>, Error> {
+        self.insert_with_expiry(key, val, Some(ttl), redis_conn)
+    }
 
-        probe!(
+    // Inserts all entries in one script, so readers see either none or all of them.
+    // Returns the new etag of each entry, in order.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(entries = entries.len()))
+    )]
+    pub fn insert_many<C: redis::ConnectionLike>(
+        &self,
+        entries: Vec<(&str, T)>,
+        redis_conn: &mut C,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let trace_id = &TraceId::new();
+
+        probe_lazy!(
             ccache,
             store,
-            trace::Event::new("insert", "start", key, &uuid.to_string()).as_ptr()
+            trace::Event::new("insert_many", "start", "")
+                .with_trace_id(trace_id)
+                .as_ptr()
         );
 
-        let val_arc = Arc::new(val);
-        let mut map = self.map.write_guard(&key.to_string());
-        let etag = self.insert_to_redis(uuid, key, val_arc.clone(), redis_conn)?;
+        let script = Script::new(INSERT_MANY_TO_REDIS_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        let mut sizes = Vec::with_capacity(entries.len());
+        for (key, val) in entries.iter() {
+            let val = val.serialize(&self.coder_config).map_err(Error::encode)?;
+            sizes.push(val.len());
+            invocation.key(*key).arg(val);
+        }
+        if self.uses_leases() {
+            for (key, _) in entries.iter() {
+                invocation.key(lease_key(key));
+            }
+        }
 
-        map.insert(
-            key.to_string(),
-            Arc::new(DataInner(etag.clone(), val_arc.clone())),
-        );
+        let etags: Vec<Vec<u8>> = loop {
+            match to_write_reply(invocation.invoke(redis_conn)?)? {
+                WriteReply::Done(etags) => break etags,
+                WriteReply::Wait(wait) => std::thread::sleep(wait),
+            }
+        };
+        if etags.len() != entries.len() {
+            return Err(Error::Protocol(format!(
+                "expected {} etags, got {}",
+                entries.len(),
+                etags.len()
+            )));
+        }
+
+        for (((key, val), etag), size) in entries.into_iter().zip(etags.iter()).zip(sizes) {
+            trace_event!(key, size, etag = %String::from_utf8_lossy(etag), "inserted");
+            self.map.publish(
+                key,
+                Arc::new(DataInner::new(etag.clone(), Arc::new(val), size, None)),
+            );
+        }
 
-        probe!(
+        probe_lazy!(
             ccache,
             store,
-            trace::Event::new("insert", "end", key, &uuid.to_string()).as_ptr()
+            trace::Event::new("insert_many", "end", "")
+                .with_trace_id(trace_id)
+                .as_ptr()
         );
 
-        Ok(etag)
+        Ok(etags)
     }
 
-    #[inline]
-    pub fn get(
+    // Inserts only if the etag of key in Redis is still expected_etag (None: the key must not exist),
+    // e.g. the etag from get_with_etag. The key keeps its expiry, if any.
+    // Returns the new etag, or Error::Conflict with the current one.
+    pub fn insert_if_match<C: redis::ConnectionLike>(
         &self,
         key: &str,
-        redis_conn: &mut redis::Connection,
-    ) -> Result<GetResult<Arc<T>>, CcacheRedisError> {
-        let uuid = Uuid::new_v4();
+        val: T,
+        expected_etag: Option<&[u8]>,
+        redis_conn: &mut C,
+    ) -> Result<Vec<u8>, Error> {
+        let trace_id = &TraceId::new();
 
-        probe!(
+        probe_lazy!(
             ccache,
             store,
-            trace::Event::new("get", "start", key, &uuid.to_string()).as_ptr()
+            trace::Event::new("insert_if_match", "start", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
         );
 
-        let map = self.map.read_guard(&key.to_string());
+        let encoded = val.serialize(&self.coder_config).map_err(Error::encode)?;
+        let size = encoded.len();
 
-        let tag = ETAG_UNCHANGED.to_vec();
-        let (etag, val) = match map.get(key) {
-            Some(d) => (d.etag(), Some(d.val())),
-            None => (&tag, None),
+        let script = Script::new(INSERT_IF_MATCH_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation
+            .key(key)
+            .arg(encoded)
+            .arg(expected_etag.unwrap_or_default());
+        self.lease_key(&mut invocation, key);
+
+        let result = loop {
+            let started = Instant::now();
+            let reply: WriteReply<(i64, Option<Vec<u8>>, i64)> =
+                to_write_reply(invocation.invoke(redis_conn)?)?;
+            match reply {
+                WriteReply::Done((1, Some(etag), pttl)) => {
+                    // PTTL is -1 for a key without expiry
+                    let expires_at = u64::try_from(pttl)
+                        .ok()
+                        .map(|pttl| started + Duration::from_millis(pttl));
+                    self.map.publish(
+                        key,
+                        Arc::new(DataInner::new(
+                            etag.clone(),
+                            Arc::new(val),
+                            size,
+                            expires_at,
+                        )),
+                    );
+                    break Ok(etag);
+                }
+                WriteReply::Done((_, current_etag, _)) => {
+                    break Err(Error::Conflict { current_etag })
+                }
+                WriteReply::Wait(wait) => std::thread::sleep(wait),
+            }
         };
 
-        let request_key = (key.to_string(), String::from_utf8(etag.to_vec()).unwrap());
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("insert_if_match", "end", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
+        );
 
-        let request_read_shard = self.request_condvar.read_guard(&request_key);
+        result
+    }
 
-        match self
-            .request_condvar
-            .get_through_shard(&request_key, &request_read_shard)
-        {
-            // request is undergoing, wait for the request
-            Some(pair) => {
-                drop(request_read_shard);
-                return self.wait_for_request(pair.clone(), val);
-            }
-            None => {
-                let pair: Arc<(Mutex<RedisMessage<T>>, Condvar)> =
-                    Arc::new((Mutex::new(RedisMessage::new()), Condvar::new()));
-                // release read lock
-                drop(request_read_shard);
-                // require write lock
-                let mut write_shard = self.request_condvar.write_guard(&request_key);
-
-                match write_shard.insert(request_key.clone(), pair.clone()) {
-                    Some(pair) => {
-                        // inserted by other thread, drop lock and wait for request
-                        drop(write_shard);
-                        return self.wait_for_request(pair.clone(), val);
-                    }
-                    None => {
-                        drop(write_shard);
-                        // inserted, do request
-                        let (lock, cvar) = &*pair;
-
-                        match request_through_etag(uuid, key, etag, redis_conn) {
-                            Ok(RequestThroughLocalResult::Unchanged) => {
-                                probe!(
-                                    ccache,
-                                    store,
-                                    trace::Event::new("get", "end", key, &uuid.to_string())
-                                        .as_ptr()
-                                );
-
-                                let mut message = lock.lock().unwrap();
-                                message.notified = true;
-                                message.redis_result = Some(Arc::new(RedisResult::Unchanged));
-
-                                // acquire write, blocks all read, then cvar.notify_all() will notify all waiting threads
-                                self.request_condvar
-                                    .write_guard(&request_key)
-                                    .remove(&request_key);
-                                cvar.notify_all();
-
-                                // map' shard write lock release here
-                                return Ok(GetResult::Unchanged(val.unwrap().clone()));
-                            }
-                            Ok(RequestThroughLocalResult::None) => {
-                                probe!(
-                                    ccache,
-                                    store,
-                                    trace::Event::new("get", "end", key, &uuid.to_string())
-                                        .as_ptr()
-                                );
-
-                                let mut message = lock.lock().unwrap();
-                                message.notified = true;
-                                message.redis_result = Some(Arc::new(RedisResult::None));
-
-                                self.request_condvar
-                                    .write_guard(&request_key)
-                                    .remove(&request_key);
-                                cvar.notify_all();
-
-                                return Ok(GetResult::None);
-                            }
-                            Ok(RequestThroughLocalResult::New(val, etag)) => {
-                                let (decoded, _): (T, usize) =
-                                    T::deserialize(&val, &self.coder_config).unwrap();
-                                let decoded_arc = Arc::new(decoded);
-
-                                // release read lock, acquire write lock and block read
-                                drop(map);
-                                let mut map = self.map.write_guard(&key.to_string());
-                                map.insert(
-                                    key.to_string(),
-                                    Arc::new(DataInner(etag, decoded_arc.clone())),
-                                );
-
-                                probe!(
-                                    ccache,
-                                    store,
-                                    trace::Event::new("get", "end", key, &uuid.to_string())
-                                        .as_ptr()
-                                );
-
-                                let mut message = lock.lock().unwrap();
-                                message.notified = true;
-                                message.redis_result =
-                                    Some(Arc::new(RedisResult::New(decoded_arc.clone())));
-
-                                self.request_condvar
-                                    .write_guard(&request_key)
-                                    .remove(&request_key);
-                                cvar.notify_all();
-
-                                return Ok(GetResult::New(decoded_arc));
-                            }
-                            Err(e) => {
-                                probe!(
-                                    ccache,
-                                    store,
-                                    trace::Event::new("get", "end", key, &uuid.to_string())
-                                        .as_ptr()
-                                );
-
-                                let mut message = lock.lock().unwrap();
-                                message.notified = true;
-                                let ccache_error: CcacheRedisError = e.into();
-                                message.redis_result =
-                                    Some(Arc::new(RedisResult::Error(ccache_error.clone())));
-
-                                self.request_condvar
-                                    .write_guard(&request_key)
-                                    .remove(&request_key);
-                                cvar.notify_all();
-
-                                return Err(ccache_error);
-                            }
-                        }
-                    }
-                }
+    // Read-modify-write of key: f gets the current value (None if the key doesn't exist) and returns the new one,
+    // which is written only if nobody changed key in between. Otherwise f runs again on the newer value,
+    // at most max_update_retries more times, then the last Error::Conflict is returned.
+    // The key keeps its expiry, if any. Returns the new etag.
+    pub fn update<C, F>(&self, key: &str, mut f: F, redis_conn: &mut C) -> Result<Vec<u8>, Error>
+    where
+        C: redis::ConnectionLike,
+        F: FnMut(Option<&T>) -> T,
+    {
+        let mut retries = 0;
+
+        loop {
+            let current = match self.get_with_etag(key, redis_conn)? {
+                GetResult::None => None,
+                GetResult::Unchanged(current)
+                | GetResult::New(current)
+                | GetResult::Unvalidated(current) => Some(current),
+                // can't write without Redis anyway
+                GetResult::Stale(_, e) => return Err(e),
+            };
+            let new_val = f(current.as_ref().map(|(val, _)| &**val));
+            let expected_etag = current.as_ref().map(|(_, etag)| etag.as_slice());
+
+            match self.insert_if_match(key, new_val, expected_etag, redis_conn) {
+                Err(Error::Conflict { .. }) if retries < self.max_update_retries => retries += 1,
+                result => return result,
             }
         }
     }
 
-    fn wait_for_request_handle_redis_result(
+    // Gets key, and on a miss inserts the value of loader. Across the processes sharing Redis,
+    // a lock key lets one process run loader at a time, the others wait for its value.
+    // Within a process, concurrent calls share one load. A value written meanwhile wins over the loaded one.
+    // Waiting for the load of another process gives up with Error::Timeout after load_lock.
+    pub fn get_or_load<C, F, E>(
         &self,
-        val: Option<Arc<T>>,
-        redis_result: &mut Option<Arc<RedisResult<T>>>,
-    ) -> Result<GetResult<Arc<T>>, CcacheRedisError> {
-        match redis_result {
-            Some(arc_result) => match &**arc_result {
-                RedisResult::None => {
-                    return Ok(GetResult::None);
-                }
-                RedisResult::Unchanged => {
-                    return Ok(GetResult::Unchanged(val.unwrap().clone()));
-                }
-                RedisResult::New(arc_value) => {
-                    return Ok(GetResult::New(arc_value.clone()));
+        key: &str,
+        loader: F,
+        redis_conn: &mut C,
+    ) -> Result<Arc<T>, Error>
+    where
+        C: redis::ConnectionLike,
+        F: FnOnce() -> Result<T, E>,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        if let Some(entry) = self.fetch(key, redis_conn)? {
+            return Ok(entry.val());
+        }
+
+        let trace_id = &TraceId::new();
+
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("get_or_load", "start", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
+        );
+
+        let request_key = RequestKey::Load(key.to_string());
+        let mut flight = self.join_request(&request_key);
+        let result = loop {
+            match flight {
+                // the leader may wait up to load_lock for another process, and then load itself
+                Flight::Follower(slot) => {
+                    match self.wait_for_request(&slot, None, self.load_lock) {
+                        // the leader was dropped without a result, like a timeout somebody has to load
+                        Some(Err(Error::Aborted)) | None => {
+                            flight = self.take_over(trace_id, key, &request_key, &slot)
+                        }
+                        Some(result) => {
+                            break result.and_then(|loaded| match loaded {
+                                GetResult::New(entry) => Ok(entry),
+                                _ => Err(Error::Protocol(
+                                    "load finished without a value".to_string(),
+                                )),
+                            })
+                        }
+                    }
                 }
-                RedisResult::Error(e) => {
-                    return Err(e.clone());
+                Flight::Leader(leader) => {
+                    let result = self.load(trace_id, key, loader, redis_conn);
+                    leader.finish(match &result {
+                        Ok(entry) => RedisResult::New(entry.clone()),
+                        Err(e) => RedisResult::Error(e.clone()),
+                    });
+
+                    break result;
                 }
-            },
-            None => {
-                panic!("Message result is None");
             }
-        }
+        };
+
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("get_or_load", "end", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
+        );
+
+        Ok(result?.val())
     }
 
-    fn wait_for_request(
+    // Remaining TTL of the local copy of key, None if it isn't cached or never expires.
+    pub fn ttl(&self, key: &str) -> Option<Duration> {
+        let map = self.map.read_guard(key);
+
+        self.map.get(&map, key)?.ttl()
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "insert", level = "debug", skip_all, fields(key = %key))
+    )]
+    fn insert_with_expiry(
         &self,
-        pair: Arc<(Mutex<RedisMessage<T>>, Condvar)>,
-        val: Option<Arc<T>>,
-    ) -> Result<GetResult<Arc<T>>, CcacheRedisError> {
-        let (lock, cvar) = &*pair.clone();
-        let mut message = lock.lock().unwrap();
+        key: &str,
+        val: T,
+        ttl: Option<Duration>,
+        redis_conn: &mut dyn redis::ConnectionLike,
+    ) -> Result<Vec<u8>, Error> {
+        let trace_id = &TraceId::new();
+
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("insert", "start", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
+        );
 
-        if message.notified {
-            // this happens when
-            // request_through_etag finished
-            // then pair is getted from request_condvar
-            // message is updated in the request thread
-            return self.wait_for_request_handle_redis_result(val, &mut message.redis_result);
-        } else {
-            while !message.notified {
-                let mut message = cvar.wait(message).unwrap();
-                return self.wait_for_request_handle_redis_result(val, &mut message.redis_result);
+        let val_arc = Arc::new(val);
+        // no shard lock is held across the round trip, a concurrent insert may publish first,
+        // then the older etag of the two is the one dropped
+        let (etag, size) = loop {
+            let started = Instant::now();
+            match self.insert_to_redis(trace_id, key, val_arc.clone(), ttl, redis_conn)? {
+                WriteReply::Done((etag, size)) => {
+                    trace_event!(size, etag = %String::from_utf8_lossy(&etag), "inserted");
+                    self.map.publish(
+                        key,
+                        Arc::new(DataInner::new(
+                            etag.clone(),
+                            val_arc.clone(),
+                            size,
+                            ttl.map(|ttl| started + ttl),
+                        )),
+                    );
+                    break (etag, size);
+                }
+                WriteReply::Wait(wait) => std::thread::sleep(wait),
             }
+        };
 
-            panic!("wrong condvar");
-        }
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("insert", "end", key)
+                .with_trace_id(trace_id)
+                .with_bytes(size)
+                .as_ptr()
+        );
+
+        Ok(etag)
     }
 
-    fn insert_to_redis(
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "insert", level = "debug", skip_all, fields(key = %key))
+    )]
+    pub async fn insert_async<C: redis::aio::ConnectionLike>(
         &self,
-        uuid: Uuid,
         key: &str,
-        obj: Arc<T>,
-        redis_conn: &mut redis::Connection,
-    ) -> Result<Vec<u8>, redis::RedisError> {
-        probe!(
+        val: T,
+        redis_conn: &mut C,
+    ) -> Result<Vec<u8>, Error> {
+        let trace_id = &TraceId::new();
+
+        probe_lazy!(
             ccache,
             store,
-            trace::Event::new("insert_to_redis", "start", key, &uuid.to_string()).as_ptr()
+            trace::Event::new("insert", "start", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
         );
 
-        let val = obj.serialize(&self.coder_config).unwrap();
-        let etag = self.insert_to_redis_request(uuid, key, val, redis_conn)?;
+        let val_arc = Arc::new(val);
+        let (etag, size) = loop {
+            match self
+                .insert_to_redis_async(trace_id, key, val_arc.clone(), redis_conn)
+                .await?
+            {
+                WriteReply::Done(inserted) => break inserted,
+                WriteReply::Wait(wait) => async_std::task::sleep(wait).await,
+            }
+        };
+        trace_event!(size, etag = %String::from_utf8_lossy(&etag), "inserted");
+
+        self.map.publish(
+            key,
+            Arc::new(DataInner::new(etag.clone(), val_arc.clone(), size, None)),
+        );
 
-        probe!(
+        probe_lazy!(
             ccache,
             store,
-            trace::Event::new("insert_to_redis", "end", key, &uuid.to_string()).as_ptr()
+            trace::Event::new("insert", "end", key)
+                .with_trace_id(trace_id)
+                .with_bytes(size)
+                .as_ptr()
         );
 
-        Ok(etag.clone())
+        Ok(etag)
     }
 
-    fn insert_to_redis_request(
+    // Removes the key from Redis and from the local map, returns whether Redis had the key.
+    // Other processes get GetResult::None on their next get, as their etag no longer matches.
+    pub fn remove<C: redis::ConnectionLike>(
         &self,
-        uuid: Uuid,
         key: &str,
-        val: Vec<u8>,
-        redis_conn: &mut redis::Connection,
-    ) -> Result<Vec<u8>, redis::RedisError> {
-        probe!(
+        redis_conn: &mut C,
+    ) -> Result<bool, Error> {
+        let trace_id = &TraceId::new();
+
+        probe_lazy!(
             ccache,
             store,
-            trace::Event::new("insert_to_redis_request", "start", key, &uuid.to_string()).as_ptr()
+            trace::Event::new("remove", "start", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
         );
 
-        let result: Result<Vec<u8>, redis::RedisError> = Script::new(INSERT_TO_REDIS_SCRIPT)
-            .key(key)
-            .arg(val)
-            .invoke(redis_conn);
+        let local_version = self.local_entry(key).map_or(0, |entry| entry.version());
+        let script = Script::new(REMOVE_FROM_REDIS_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation.key(key);
+        self.lease_key(&mut invocation, key);
+
+        let removed: Option<Vec<u8>> = loop {
+            match to_write_reply(invocation.invoke(redis_conn)?)? {
+                WriteReply::Done(removed) => break removed,
+                WriteReply::Wait(wait) => std::thread::sleep(wait),
+            }
+        };
 
-        probe!(
+        // an insert in this process may have published a later version meanwhile, it stays
+        let removed_version = removed.as_deref().map_or(0, version_of);
+        self.map
+            .remove_through(key, local_version.max(removed_version));
+
+        probe_lazy!(
             ccache,
             store,
-            trace::Event::new("insert_to_redis_request", "end", key, &uuid.to_string()).as_ptr()
+            trace::Event::new("remove", "end", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
         );
 
-        result
+        Ok(removed.is_some())
     }
-}
 
-#[inline]
-fn request_through_etag(
-    uuid: Uuid,
-    key: &str,
-    etag: &Vec<u8>,
-    conn: &mut redis::Connection,
-) -> Result<RequestThroughLocalResult, redis::RedisError> {
-    let redis_result = get_from_redis_through_etag(uuid, key, etag, conn)?;
-    if unlikely(redis_result.is_empty()) {
-        Ok(RequestThroughLocalResult::None)
-    } else if likely(redis_result.get("etag").unwrap() == ETAG_UNCHANGED) {
-        Ok(RequestThroughLocalResult::Unchanged)
-    } else {
-        let val = redis_result.get("val").unwrap().to_vec();
-        let etag = redis_result.get("etag").unwrap();
-        Ok(RequestThroughLocalResult::New(val, etag.clone()))
+    // A get whose local copy is unchanged doesn't allocate in ccache, see tests/allocations.rs.
+    // redis-rs still allocates the reply it parses when the copy is validated with a round trip.
+    #[inline]
+    pub fn get<C: redis::ConnectionLike>(
+        &self,
+        key: &str,
+        redis_conn: &mut C,
+    ) -> Result<GetResult<Arc<T>>, Error> {
+        Ok(self
+            .get_entry(key, Duration::ZERO, redis_conn)?
+            .map(|entry| entry.val()))
     }
-}
 
-#[inline]
-fn get_from_redis_through_etag(
-    uuid: Uuid,
-    key: &str,
-    etag: &Vec<u8>,
-    conn: &mut redis::Connection,
-) -> Result<HashMap<String, Vec<u8>>, redis::RedisError> {
-    probe!(
-        ccache,
-        store,
-        trace::Event::new(
-            "get_from_redis_through_etag",
-            "start",
-            key,
-            &uuid.to_string()
-        )
-        .as_ptr()
-    );
+    // Like get, but a recently validated local copy is returned without a Redis round trip.
+    pub fn get_with_options<C: redis::ConnectionLike>(
+        &self,
+        key: &str,
+        options: &ReadOptions,
+        redis_conn: &mut C,
+    ) -> Result<GetResult<Arc<T>>, Error> {
+        Ok(self
+            .get_entry(key, options.max_staleness, redis_conn)?
+            .map(|entry| entry.val()))
+    }
 
-    // NOTICE HGETALLETAG is in a self build Redis, it works like GET_FROM_REDIS_SCRIPT
-    // see: https://github.com/yfractal/redis/commit/629fbc49a7f6167a6f7980e932e7f3554212b031
+    // Like get, also returns the etag of the value, which is what insert_if_match expects.
+    pub fn get_with_etag<C: redis::ConnectionLike>(
+        &self,
+        key: &str,
+        redis_conn: &mut C,
+    ) -> Result<GetResult<Versioned<T>>, Error> {
+        Ok(self
+            .get_entry(key, Duration::ZERO, redis_conn)?
+            .map(|entry| (entry.val(), entry.etag().clone())))
+    }
+
+    #[inline]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "get", level = "debug", skip_all, fields(key = %key))
+    )]
+    fn get_entry(
+        &self,
+        key: &str,
+        max_staleness: Duration,
+        redis_conn: &mut dyn redis::ConnectionLike,
+    ) -> EntryResult<T> {
+        let trace_id = &TraceId::new();
+
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("get", "start", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
+        );
+
+        // the shard lock is released before any I/O, a slow request doesn't block the other keys of the shard
+        let entry = self.local_entry(key);
+
+        if let Some(d) = &entry {
+            if self.is_fresh(key, d) {
+                probe_lazy!(
+                    ccache,
+                    store,
+                    trace::Event::new("get", "fresh", key)
+                        .with_trace_id(trace_id)
+                        .with_result(EventResult::Unchanged)
+                        .as_ptr()
+                );
+
+                let result = Ok(GetResult::Unchanged(d.clone()));
+                self.observe(key, &result);
+                return result;
+            }
+
+            if d.validated_within(max_staleness) {
+                probe_lazy!(
+                    ccache,
+                    store,
+                    trace::Event::new("get", "unvalidated", key)
+                        .with_trace_id(trace_id)
+                        .with_result(EventResult::Unvalidated)
+                        .as_ptr()
+                );
+
+                let result = Ok(GetResult::Unvalidated(d.clone()));
+                self.observe(key, &result);
+                return result;
+            }
+        }
+
+        let request_key = RequestKey::of(key, &entry);
+        let tracked_seq = self.tracked_seq(key);
+
+        let result = match self.join_request(&request_key) {
+            // request is undergoing, wait for the request
+            Flight::Follower(slot) => {
+                self.follow(trace_id, key, &request_key, slot, entry, redis_conn)
+            }
+            Flight::Leader(leader) => {
+                let result = self.request(trace_id, key, local_etag(&entry), redis_conn);
+
+                self.finish_request(trace_id, key, leader, entry, tracked_seq, result)
+            }
+        };
+        self.observe(key, &result);
+
+        result
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "get", level = "debug", skip_all, fields(key = %key))
+    )]
+    pub async fn get_async<C: redis::aio::ConnectionLike>(
+        &self,
+        key: &str,
+        redis_conn: &mut C,
+    ) -> Result<GetResult<Arc<T>>, Error> {
+        let trace_id = &TraceId::new();
+
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("get", "start", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
+        );
+
+        // clone the entry out, the shard lock can't be held across an await point
+        let entry = self.local_entry(key);
+        if let Some(d) = entry.as_ref().filter(|d| self.is_fresh(key, d)) {
+            probe_lazy!(
+                ccache,
+                store,
+                trace::Event::new("get", "fresh", key)
+                    .with_trace_id(trace_id)
+                    .with_result(EventResult::Unchanged)
+                    .as_ptr()
+            );
+
+            let result = Ok(GetResult::Unchanged(d.clone()));
+            self.observe(key, &result);
+            return Ok(result?.map(|entry| entry.val()));
+        }
+        let request_key = RequestKey::of(key, &entry);
+        let tracked_seq = self.tracked_seq(key);
+
+        let mut flight = self.join_request(&request_key);
+        let result = loop {
+            match flight {
+                Flight::Follower(slot) => {
+                    match self.wait_for_request_async(&slot, entry.clone()).await {
+                        // see follow
+                        Some(Err(Error::Aborted)) | None => {
+                            flight = self.take_over(trace_id, key, &request_key, &slot)
+                        }
+                        Some(result) => {
+                            stats::incr(&self.counters.stripe().coalesced);
+                            trace_followed(trace_id, key, &result);
+                            break result;
+                        }
+                    }
+                }
+                Flight::Leader(leader) => {
+                    let result = match self.acquire() {
+                        Ok(permit) => {
+                            let started = Instant::now();
+                            let result = request_through_etag_async(
+                                trace_id,
+                                key,
+                                local_etag(&entry),
+                                self.read_lease,
+                                redis_conn,
+                            )
+                            .await;
+                            self.counters
+                                .stripe()
+                                .redis_latency
+                                .observe(started.elapsed());
+                            self.record(permit, &result);
+                            result
+                        }
+                        Err(e) => Err(e),
+                    };
+
+                    break self.finish_request(trace_id, key, leader, entry, tracked_seq, result);
+                }
+            }
+        };
+        self.observe(key, &result);
+
+        Ok(result?.map(|entry| entry.val()))
+    }
+
+    // Gets several keys, keys which need a request are validated in one round trip.
+    // Keys with a request already undergoing wait for it, like get does.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(keys = keys.len()))
+    )]
+    pub fn get_many<C: redis::ConnectionLike>(
+        &self,
+        keys: &[&str],
+        redis_conn: &mut C,
+    ) -> Result<Vec<GetResult<Arc<T>>>, Error> {
+        let trace_id = &TraceId::new();
+
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("get_many", "start", "")
+                .with_trace_id(trace_id)
+                .as_ptr()
+        );
+
+        let mut results: Vec<Option<EntryResult<T>>> = keys.iter().map(|_| None).collect();
+        let mut leaders = Vec::new();
+        let mut followers = Vec::new();
+
+        for (i, key) in keys.iter().enumerate() {
+            let entry = self.local_entry(key);
+            if let Some(d) = entry.as_ref().filter(|d| self.is_fresh(key, d)) {
+                results[i] = Some(Ok(GetResult::Unchanged(d.clone())));
+                continue;
+            }
+            let request_key = RequestKey::of(key, &entry);
+
+            match self.join_request(&request_key) {
+                Flight::Leader(leader) => {
+                    let tracked_seq = self.tracked_seq(key);
+                    leaders.push((i, leader, entry, tracked_seq))
+                }
+                Flight::Follower(slot) => followers.push((i, request_key, slot, entry)),
+            }
+        }
+
+        if !leaders.is_empty() {
+            let started = Instant::now();
+            let leader_keys: Vec<&str> = leaders.iter().map(|l| keys[l.0]).collect();
+            let etags: Vec<&[u8]> = leaders.iter().map(|l| local_etag(&l.2)).collect();
+
+            let batch = self
+                .acquire()
+                .and_then(|permit| {
+                    let batch = get_many_from_redis_through_etag(
+                        trace_id,
+                        &leader_keys,
+                        &etags,
+                        self.read_lease,
+                        redis_conn,
+                    )
+                    .map_err(Error::from);
+                    self.counters
+                        .stripe()
+                        .redis_latency
+                        .observe(started.elapsed());
+                    self.record(permit, &batch);
+                    batch
+                })
+                .and_then(|batch| {
+                    if batch.len() == leaders.len() {
+                        Ok(batch)
+                    } else {
+                        Err(Error::Protocol(format!(
+                            "expected {} results from Redis, got {}",
+                            leaders.len(),
+                            batch.len()
+                        )))
+                    }
+                });
+
+            // every leader must publish, even on error, before waiting for followers,
+            // a duplicated key follows a leader of this very call
+            match batch {
+                Ok(batch) => {
+                    for ((i, leader, entry, tracked_seq), redis_result) in
+                        leaders.into_iter().zip(batch)
+                    {
+                        let result = to_request_through_local_result(started, &redis_result);
+                        results[i] = Some(self.finish_request(
+                            trace_id,
+                            keys[i],
+                            leader,
+                            entry,
+                            tracked_seq,
+                            result,
+                        ));
+                    }
+                }
+                Err(e) => {
+                    for (i, leader, entry, tracked_seq) in leaders {
+                        results[i] = Some(self.finish_request(
+                            trace_id,
+                            keys[i],
+                            leader,
+                            entry,
+                            tracked_seq,
+                            Err(e.clone()),
+                        ));
+                    }
+                }
+            }
+        }
+
+        for (i, request_key, slot, entry) in followers {
+            results[i] =
+                Some(self.follow(trace_id, keys[i], &request_key, slot, entry, redis_conn));
+        }
+        for (key, result) in keys.iter().zip(&results) {
+            if let Some(result) = result {
+                self.observe(key, result);
+            }
+        }
+
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("get_many", "end", "")
+                .with_trace_id(trace_id)
+                .as_ptr()
+        );
+
+        results
+            .into_iter()
+            .map(|result| Ok(result.unwrap()?.map(|entry| entry.val())))
+            .collect()
+    }
+
+    // Whether the local copy can be served without asking Redis, because it holds a read lease,
+    // or tracking is healthy and nothing invalidated key since the copy was validated.
+    fn is_fresh(&self, key: &str, entry: &DataInner<T>) -> bool {
+        entry.is_leased()
+            || self
+                .tracker
+                .as_ref()
+                .is_some_and(|tracker| tracker.is_clean(key, entry.tracked_seq()))
+    }
+
+    // read before the request validating key, see Tracker
+    fn tracked_seq(&self, key: &str) -> u64 {
+        match &self.tracker {
+            Some(tracker) => tracker.seq(key),
+            None => 0,
+        }
+    }
+
+    fn local_entry(&self, key: &str) -> Option<Arc<DataInner<T>>> {
+        self.map.get(&self.map.read_guard(key), key)
+    }
+
+    fn fetch(
+        &self,
+        key: &str,
+        redis_conn: &mut dyn redis::ConnectionLike,
+    ) -> Result<Option<Arc<DataInner<T>>>, Error> {
+        match self.get_entry(key, Duration::ZERO, redis_conn)? {
+            GetResult::None => Ok(None),
+            GetResult::Unchanged(entry)
+            | GetResult::New(entry)
+            | GetResult::Unvalidated(entry)
+            | GetResult::Stale(entry, _) => Ok(Some(entry)),
+        }
+    }
+
+    // Runs loader once it holds the lock key of key, or returns the value of the process holding it.
+    // Gives up with Error::Timeout after waiting load_lock for it.
+    fn load<C, F, E>(
+        &self,
+        trace_id: &TraceId,
+        key: &str,
+        loader: F,
+        redis_conn: &mut C,
+    ) -> Result<Arc<DataInner<T>>, Error>
+    where
+        C: redis::ConnectionLike,
+        F: FnOnce() -> Result<T, E>,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let lock_key = format!("ccache:load:{}", key);
+        let token = &trace_id.as_bytes()[..];
+        // by then the lock of the process loading has expired, unless it was taken again
+        let deadline = Instant::now() + self.load_lock;
+        let mut poll_interval = LOAD_POLL_INTERVAL;
+
+        loop {
+            let locked: Option<String> = redis::cmd("SET")
+                .arg(&lock_key)
+                .arg(token)
+                .arg("NX")
+                .arg("PX")
+                .arg(self.load_lock.as_millis().max(1) as u64)
+                .query(redis_conn)?;
+
+            if locked.is_some() {
+                let result = self.load_locked(key, loader, redis_conn);
+                // if unlocking fails, the lock expires by itself
+                let _ = Script::new(UNLOCK_SCRIPT)
+                    .key(&lock_key)
+                    .arg(token)
+                    .invoke::<i64>(redis_conn);
+
+                return result;
+            }
+
+            if poll_interval == LOAD_POLL_INTERVAL {
+                probe_lazy!(
+                    ccache,
+                    store,
+                    trace::Event::new("get_or_load", "wait", key)
+                        .with_trace_id(trace_id)
+                        .as_ptr()
+                );
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(Error::Timeout(format!(
+                    "{} was locked by another load for {:?}",
+                    key, self.load_lock
+                )));
+            }
+            std::thread::sleep(poll_interval.min(remaining));
+            poll_interval = (poll_interval * 2).min(MAX_LOAD_POLL_INTERVAL);
+
+            // polled without get, so waiting isn't counted as gets nor traced as such
+            let loaded: bool = redis::cmd("EXISTS").arg(key).query(redis_conn)?;
+            if loaded {
+                if let Some(entry) = self.fetch(key, redis_conn)? {
+                    return Ok(entry);
+                }
+            }
+        }
+    }
+
+    fn load_locked<C, F, E>(
+        &self,
+        key: &str,
+        loader: F,
+        redis_conn: &mut C,
+    ) -> Result<Arc<DataInner<T>>, Error>
+    where
+        C: redis::ConnectionLike,
+        F: FnOnce() -> Result<T, E>,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        // loaded by the previous holder of the lock
+        if let Some(entry) = self.fetch(key, redis_conn)? {
+            return Ok(entry);
+        }
+
+        let val = loader().map_err(Error::load)?;
+        let loaded = match self.insert_if_match(key, val, None, redis_conn) {
+            Ok(_) => self.local_entry(key),
+            // written by a writer which doesn't take the lock
+            Err(Error::Conflict { .. }) => None,
+            Err(e) => return Err(e),
+        };
+
+        match loaded {
+            Some(entry) => Ok(entry),
+            None => self
+                .fetch(key, redis_conn)?
+                .ok_or(Error::Conflict { current_etag: None }),
+        }
+    }
+
+    // Registers the current caller as the one doing the request for `request_key`,
+    // or returns the slot of the request which is already undergoing.
+    fn join_request(&self, request_key: &RequestKey<T>) -> Flight<'_, T> {
+        if let Some(slot) = self
+            .request_condvar
+            .read_guard(request_key)
+            .get(request_key)
+            .and_then(|pending| pending.slot.clone())
+        {
+            return Flight::Follower(slot);
+        }
+
+        // no request, or nobody waits for it yet
+        let mut write_shard = self.request_condvar.write_guard(request_key);
+
+        match write_shard.entry(request_key.clone()) {
+            Entry::Occupied(mut entry) => Flight::Follower(entry.get_mut().slot()),
+            Entry::Vacant(entry) => {
+                let id = self.next_leader.fetch_add(1, Ordering::Relaxed);
+                entry.insert(Pending::new(id));
+
+                Flight::Leader(Leader::new(self, request_key.clone(), id))
+            }
+        }
+    }
+
+    // Called by a waiter whose leader didn't publish within wait_timeout. Unless another waiter did already,
+    // the caller becomes the leader, so later gets don't join the stuck one. The waiters of the stuck leader
+    // get the result of whichever of the two publishes first.
+    fn take_over(
+        &self,
+        trace_id: &TraceId,
+        key: &str,
+        request_key: &RequestKey<T>,
+        stuck: &Arc<RequestSlot<T>>,
+    ) -> Flight<'_, T> {
+        let mut write_shard = self.request_condvar.write_guard(request_key);
+        let id = self.next_leader.fetch_add(1, Ordering::Relaxed);
+
+        match write_shard.entry(request_key.clone()) {
+            Entry::Occupied(mut entry) => {
+                let pending = entry.get_mut();
+                if !pending
+                    .slot
+                    .as_ref()
+                    .is_some_and(|slot| Arc::ptr_eq(slot, stuck))
+                {
+                    return Flight::Follower(pending.slot());
+                }
+
+                let stuck_leader = std::mem::replace(&mut pending.leader, id);
+                pending.orphans.push((stuck_leader, stuck.clone()));
+                pending.slot = None;
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Pending::new(id));
+            }
+        }
+
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("get", "take_over", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
+        );
+
+        Flight::Leader(Leader::new(self, request_key.clone(), id))
+    }
+
+    // Waits for the leader of request_key, takes over if it doesn't publish in time or is aborted.
+    fn follow(
+        &self,
+        trace_id: &TraceId,
+        key: &str,
+        request_key: &RequestKey<T>,
+        mut slot: Arc<RequestSlot<T>>,
+        entry: Option<Arc<DataInner<T>>>,
+        redis_conn: &mut dyn redis::ConnectionLike,
+    ) -> EntryResult<T> {
+        loop {
+            match self.wait_for_request(&slot, entry.clone(), self.wait_timeout) {
+                // the leader was dropped without a result, so like on a timeout somebody has to do the request
+                Some(Err(Error::Aborted)) | None => {}
+                Some(result) => {
+                    stats::incr(&self.counters.stripe().coalesced);
+                    trace_followed(trace_id, key, &result);
+                    return result;
+                }
+            }
+
+            match self.take_over(trace_id, key, request_key, &slot) {
+                Flight::Follower(next) => slot = next,
+                Flight::Leader(leader) => {
+                    let tracked_seq = self.tracked_seq(key);
+                    let result = self.request(trace_id, key, local_etag(&entry), redis_conn);
+
+                    return self.finish_request(trace_id, key, leader, entry, tracked_seq, result);
+                }
+            }
+        }
+    }
+
+    fn request(
+        &self,
+        trace_id: &TraceId,
+        key: &str,
+        etag: &[u8],
+        redis_conn: &mut dyn redis::ConnectionLike,
+    ) -> Result<RequestThroughLocalResult, Error> {
+        let permit = self.acquire()?;
+        let started = Instant::now();
+        let result = request_through_etag(trace_id, key, etag, self.read_lease, redis_conn);
+        self.counters
+            .stripe()
+            .redis_latency
+            .observe(started.elapsed());
+        self.record(permit, &result);
+
+        result
+    }
+
+    fn finish_request(
+        &self,
+        trace_id: &TraceId,
+        key: &str,
+        leader: Leader<'_, T>,
+        entry: Option<Arc<DataInner<T>>>,
+        tracked_seq: u64,
+        result: Result<RequestThroughLocalResult, Error>,
+    ) -> EntryResult<T> {
+        let (redis_result, rv) = match result {
+            Ok(RequestThroughLocalResult::Unchanged(leased_until)) => match entry {
+                Some(entry) => {
+                    entry.validate();
+                    entry.track(tracked_seq);
+                    if let Some(until) = leased_until {
+                        entry.lease(until);
+                    }
+
+                    (RedisResult::Unchanged, Ok(GetResult::Unchanged(entry)))
+                }
+                None => {
+                    let e = unchanged_local_miss();
+                    (RedisResult::Error(e.clone()), Err(e))
+                }
+            },
+            Ok(RequestThroughLocalResult::None) => {
+                // the Redis key was deleted or evicted, or the local copy outlived an expired one.
+                // A later version published meanwhile stays
+                let removed = match &entry {
+                    Some(entry) => self.map.remove_through(key, entry.version()),
+                    None => self.map.remove_expired(key),
+                };
+                if removed {
+                    stats::incr(&self.counters.stripe().removed_remote);
+
+                    probe_lazy!(
+                        ccache,
+                        store,
+                        trace::Event::new("get", "removed", key)
+                            .with_trace_id(trace_id)
+                            .as_ptr()
+                    );
+                }
+
+                (RedisResult::None, Ok(GetResult::None))
+            }
+            Ok(RequestThroughLocalResult::New(val, etag, expires_at, leased_until)) => {
+                stats::add(&self.counters.stripe().bytes_fetched, val.len() as u64);
+
+                let started = Instant::now();
+                let decoded = T::deserialize(&val, &self.coder_config);
+                self.counters
+                    .stripe()
+                    .decode_latency
+                    .observe(started.elapsed());
+
+                match decoded {
+                    Ok((decoded, _)) => {
+                        let entry = Arc::new(DataInner::new(
+                            etag,
+                            Arc::new(decoded),
+                            val.len(),
+                            expires_at,
+                        ));
+                        entry.track(tracked_seq);
+                        if let Some(until) = leased_until {
+                            entry.lease(until);
+                        }
+
+                        // a concurrent insert or get may have published a later version, which stays
+                        self.map.publish(key, entry.clone());
+
+                        (RedisResult::New(entry.clone()), Ok(GetResult::New(entry)))
+                    }
+                    // a corrupt payload isn't published, the next get fetches it again
+                    Err(e) => {
+                        let e = Error::decode(e);
+                        (RedisResult::Error(e.clone()), Err(e))
+                    }
+                }
+            }
+            Err(e) => (RedisResult::Error(e.clone()), self.stale_or_error(entry, e)),
+        };
+
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("get", "end", key)
+                .with_trace_id(trace_id)
+                .with_result(EventResult::of(&rv))
+                .with_bytes(match &redis_result {
+                    RedisResult::New(entry) => entry.size(),
+                    _ => 0,
+                })
+                .as_ptr()
+        );
+
+        leader.finish(redis_result);
+
+        rv
+    }
+
+    // Publishes the result of the request of leader id, removes the request unless it was taken over,
+    // and wakes the waiters.
+    fn publish(&self, request_key: &RequestKey<T>, id: u64, redis_result: RedisResult<T>) {
+        // acquire write, blocks all read, nobody joins the request after this
+        let mut write_shard = self.request_condvar.write_guard(request_key);
+        let (slot, orphans) = match write_shard.entry(request_key.clone()) {
+            Entry::Occupied(entry) if entry.get().leader == id => {
+                let pending = entry.remove();
+                (pending.slot, pending.orphans)
+            }
+            // taken over, only the waiters from before are left to it
+            Entry::Occupied(mut entry) => {
+                let orphans = &mut entry.get_mut().orphans;
+                let slot = orphans
+                    .iter()
+                    .position(|(leader, _)| *leader == id)
+                    .map(|i| orphans.swap_remove(i).1);
+                (slot, Vec::new())
+            }
+            Entry::Vacant(_) => (None, Vec::new()),
+        };
+        drop(write_shard);
+
+        if slot.is_none() && orphans.is_empty() {
+            return;
+        }
+
+        let redis_result = Arc::new(redis_result);
+        for slot in slot.iter().chain(orphans.iter().map(|(_, slot)| slot)) {
+            // may run while unwinding, a poisoned message is still fine to overwrite
+            let mut message = slot.message.lock().unwrap_or_else(PoisonError::into_inner);
+            message.notified = true;
+            message.redis_result = Some(redis_result.clone());
+            drop(message);
+
+            slot.cvar.notify_all();
+            slot.event.notify(usize::MAX);
+        }
+    }
+
+    fn wait_for_request_handle_redis_result(
+        &self,
+        entry: Option<Arc<DataInner<T>>>,
+        redis_result: &Option<Arc<RedisResult<T>>>,
+    ) -> EntryResult<T> {
+        match redis_result {
+            Some(arc_result) => match &**arc_result {
+                RedisResult::None => {
+                    return Ok(GetResult::None);
+                }
+                RedisResult::Unchanged => {
+                    return entry
+                        .map(GetResult::Unchanged)
+                        .ok_or_else(unchanged_local_miss);
+                }
+                RedisResult::New(new_entry) => {
+                    return Ok(GetResult::New(new_entry.clone()));
+                }
+                RedisResult::Error(e) => {
+                    return self.stale_or_error(entry, e.clone());
+                }
+            },
+            None => {
+                return Err(Error::Protocol(
+                    "request finished without a result".to_string(),
+                ));
+            }
+        }
+    }
+
+    // Counts the result of a get, and traces it.
+    fn observe(&self, key: &str, result: &EntryResult<T>) {
+        self.counters.result(result);
+        trace_get(key, result);
+    }
+
+    // Whether writes wait for read leases, see with_read_lease.
+    fn uses_leases(&self) -> bool {
+        !self.read_lease.is_zero()
+    }
+
+    // Adds the lease hash of key to a write script's KEYS, if the store uses leases.
+    fn lease_key(&self, invocation: &mut redis::ScriptInvocation<'_>, key: &str) {
+        if self.uses_leases() {
+            invocation.key(lease_key(key));
+        }
+    }
+
+    fn acquire(&self) -> Result<Option<Permit<'_>>, Error> {
+        match &self.breaker {
+            Some(breaker) => breaker.acquire().map(Some).ok_or(Error::CircuitOpen),
+            None => Ok(None),
+        }
+    }
+
+    fn record<R>(&self, permit: Option<Permit<'_>>, result: &Result<R, Error>) {
+        if let Some(permit) = permit {
+            permit.record(result);
+        }
+    }
+
+    fn stale_or_error(&self, entry: Option<Arc<DataInner<T>>>, e: Error) -> EntryResult<T> {
+        match (entry, self.stale_if_error) {
+            (Some(entry), Some(max_age))
+                if (e.is_unavailable() || matches!(e, Error::CircuitOpen))
+                    && entry.validated_within(max_age) =>
+            {
+                Ok(GetResult::Stale(entry, e))
+            }
+            _ => Err(e),
+        }
+    }
+
+    // None once timeout passed without the result.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "wait", level = "debug", skip_all)
+    )]
+    fn wait_for_request(
+        &self,
+        slot: &RequestSlot<T>,
+        entry: Option<Arc<DataInner<T>>>,
+        timeout: Duration,
+    ) -> Option<EntryResult<T>> {
+        let deadline = Instant::now() + timeout;
+        let mut message = slot.message.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // notified is already set when request_through_etag finished
+        // between getting the slot from request_condvar and locking the message
+        while !message.notified {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                trace_event!("timed out");
+                return None;
+            }
+
+            message = slot
+                .cvar
+                .wait_timeout(message, remaining)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+
+        Some(self.wait_for_request_handle_redis_result(entry, &message.redis_result))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "wait", level = "debug", skip_all)
+    )]
+    async fn wait_for_request_async(
+        &self,
+        slot: &RequestSlot<T>,
+        entry: Option<Arc<DataInner<T>>>,
+    ) -> Option<EntryResult<T>> {
+        let deadline = Instant::now() + self.wait_timeout;
+
+        loop {
+            // listen before checking, so a notification between the check and the await isn't lost
+            let listener = slot.event.listen();
+
+            {
+                let message = slot.message.lock().unwrap_or_else(PoisonError::into_inner);
+                if message.notified {
+                    return Some(
+                        self.wait_for_request_handle_redis_result(entry, &message.redis_result),
+                    );
+                }
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                trace_event!("timed out");
+                return None;
+            }
+            // a timeout is noticed by the next iteration
+            let _ = async_std::future::timeout(remaining, listener).await;
+        }
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(key = %key))
+    )]
+    fn insert_to_redis(
+        &self,
+        trace_id: &TraceId,
+        key: &str,
+        obj: Arc<T>,
+        ttl: Option<Duration>,
+        redis_conn: &mut dyn redis::ConnectionLike,
+    ) -> Result<WriteReply<(Vec<u8>, usize)>, Error> {
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("insert_to_redis", "start", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
+        );
+
+        let val = obj.serialize(&self.coder_config).map_err(Error::encode)?;
+        let size = val.len();
+        let reply = self.insert_to_redis_request(trace_id, key, val, ttl, redis_conn)?;
+
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("insert_to_redis", "end", key)
+                .with_trace_id(trace_id)
+                .with_bytes(size)
+                .as_ptr()
+        );
+
+        Ok(reply.map(|etag| (etag, size)))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "insert_to_redis",
+            level = "debug",
+            skip_all,
+            fields(key = %key)
+        )
+    )]
+    async fn insert_to_redis_async<C: redis::aio::ConnectionLike>(
+        &self,
+        trace_id: &TraceId,
+        key: &str,
+        obj: Arc<T>,
+        redis_conn: &mut C,
+    ) -> Result<WriteReply<(Vec<u8>, usize)>, Error> {
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("insert_to_redis", "start", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
+        );
+
+        let val = obj.serialize(&self.coder_config).map_err(Error::encode)?;
+        let size = val.len();
+
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("insert_to_redis_request", "start", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
+        );
+
+        let script = Script::new(INSERT_TO_REDIS_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation.key(key).arg(val).arg(ttl_millis(None));
+        self.lease_key(&mut invocation, key);
+        let reply: WriteReply<Vec<u8>> =
+            to_write_reply(invocation.invoke_async(redis_conn).await?)?;
+
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("insert_to_redis_request", "end", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
+        );
+
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("insert_to_redis", "end", key)
+                .with_trace_id(trace_id)
+                .with_bytes(size)
+                .as_ptr()
+        );
+
+        Ok(reply.map(|etag| (etag, size)))
+    }
+
+    fn insert_to_redis_request(
+        &self,
+        trace_id: &TraceId,
+        key: &str,
+        val: Vec<u8>,
+        ttl: Option<Duration>,
+        redis_conn: &mut dyn redis::ConnectionLike,
+    ) -> Result<WriteReply<Vec<u8>>, Error> {
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("insert_to_redis_request", "start", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
+        );
+
+        let script = Script::new(INSERT_TO_REDIS_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation.key(key).arg(val).arg(ttl_millis(ttl));
+        self.lease_key(&mut invocation, key);
+        let result = invocation
+            .invoke(redis_conn)
+            .map_err(Error::from)
+            .and_then(to_write_reply);
+
+        probe_lazy!(
+            ccache,
+            store,
+            trace::Event::new("insert_to_redis_request", "end", key)
+                .with_trace_id(trace_id)
+                .as_ptr()
+        );
+
+        result
+    }
+}
+
+// Emits the result of a get as a tracing event, with the size and etag of the value it returns.
+#[cfg(feature = "tracing")]
+fn trace_get<T>(key: &str, result: &EntryResult<T>) {
+    let entry = match result {
+        Ok(GetResult::Unchanged(entry))
+        | Ok(GetResult::Unvalidated(entry))
+        | Ok(GetResult::Stale(entry, _))
+        | Ok(GetResult::New(entry)) => Some(entry),
+        Ok(GetResult::None) | Err(_) => None,
+    };
+
+    trace_event!(
+        key,
+        result = EventResult::of(result).as_str(),
+        size = entry.map(|entry| entry.size()),
+        etag = entry.map(|entry| tracing::field::display(String::from_utf8_lossy(entry.etag()))),
+        error = result.as_ref().err().map(tracing::field::display),
+        "get"
+    );
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+fn trace_get<T>(_key: &str, _result: &EntryResult<T>) {}
+
+// The end event of a get which got the result of another get's request, it fetched no bytes itself.
+fn trace_followed<T>(trace_id: &TraceId, key: &str, result: &EntryResult<T>) {
+    probe_lazy!(
+        ccache,
+        store,
+        trace::Event::new("get", "end", key)
+            .with_trace_id(trace_id)
+            .with_result(EventResult::of(result))
+            .as_ptr()
+    );
+}
+
+// The etag sent for a local copy, ETAG_UNCHANGED on a local miss.
+fn local_etag<T>(entry: &Option<Arc<DataInner<T>>>) -> &[u8] {
+    match entry {
+        Some(d) => d.etag(),
+        None => ETAG_UNCHANGED,
+    }
+}
+
+#[inline]
+fn request_through_etag(
+    trace_id: &TraceId,
+    key: &str,
+    etag: &[u8],
+    lease: Duration,
+    conn: &mut dyn redis::ConnectionLike,
+) -> Result<RequestThroughLocalResult, Error> {
+    let started = Instant::now();
+    let redis_result = get_from_redis_through_etag(trace_id, key, etag, lease, conn)?;
+
+    to_request_through_local_result(started, &redis_result)
+}
+
+async fn request_through_etag_async<C: redis::aio::ConnectionLike>(
+    trace_id: &TraceId,
+    key: &str,
+    etag: &[u8],
+    lease: Duration,
+    conn: &mut C,
+) -> Result<RequestThroughLocalResult, Error> {
+    let started = Instant::now();
+    let redis_result = get_from_redis_through_etag_async(trace_id, key, etag, lease, conn).await?;
+
+    to_request_through_local_result(started, &redis_result)
+}
+
+// Parses the reply of GET_FROM_REDIS_SCRIPT in place, an unchanged reply is read without allocating.
+#[inline]
+fn to_request_through_local_result(
+    started: Instant,
+    redis_result: &redis::Value,
+) -> Result<RequestThroughLocalResult, Error> {
+    let fields = match redis_result {
+        redis::Value::Bulk(fields) => fields,
+        _ => return Err(Error::Protocol("a get reply isn't an array".to_string())),
+    };
+
+    if unlikely(fields.is_empty()) {
+        return Ok(RequestThroughLocalResult::None);
+    }
+
+    let (mut etag, mut val, mut pttl, mut lease_ms) = (None, None, None, None);
+    for pair in fields.chunks(2) {
+        match pair {
+            [redis::Value::Data(name), redis::Value::Data(value)] => match name.as_slice() {
+                b"etag" => etag = Some(value),
+                b"val" => val = Some(value),
+                b"pttl" => pttl = Some(value),
+                b"lease_ms" => lease_ms = Some(value),
+                // e.g. the lease fields of the hash
+                _ => {}
+            },
+            _ => return Err(Error::Protocol("malformed get reply".to_string())),
+        }
+    }
+
+    // Redis granted the lease after we sent the request, so it ends no earlier than started + lease_ms
+    let leased_until = lease_ms
+        .and_then(|ms| parse_u64(ms))
+        .filter(|ms| *ms > 0)
+        .map(|ms| started + Duration::from_millis(ms));
+
+    let etag = etag.ok_or_else(|| missing_field("etag"))?;
+    if likely(etag == ETAG_UNCHANGED) {
+        Ok(RequestThroughLocalResult::Unchanged(leased_until))
+    } else {
+        let val = val.ok_or_else(|| missing_field("val"))?.to_vec();
+        // PTTL is -1 for a key without expiry
+        let expires_at = pttl
+            .and_then(|pttl| parse_u64(pttl))
+            .map(|pttl| started + Duration::from_millis(pttl));
+        Ok(RequestThroughLocalResult::New(
+            val,
+            etag.clone(),
+            expires_at,
+            leased_until,
+        ))
+    }
+}
+
+fn parse_u64(bytes: &[u8]) -> Option<u64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+fn missing_field(name: &str) -> Error {
+    Error::Protocol(format!("no {} in the reply of a get", name))
+}
+
+// Redis can't reply unchanged to ETAG_UNCHANGED, unless it holds a corrupt etag
+fn unchanged_local_miss() -> Error {
+    Error::Protocol("unchanged reply to a local miss".to_string())
+}
+
+fn to_write_reply<R: redis::FromRedisValue>(
+    (wait, reply): (u64, redis::Value),
+) -> Result<WriteReply<R>, Error> {
+    if wait > 0 {
+        return Ok(WriteReply::Wait(Duration::from_millis(wait)));
+    }
+
+    Ok(WriteReply::Done(R::from_redis_value(&reply)?))
+}
+
+// zero means no expiry for INSERT_TO_REDIS_SCRIPT, so a zero ttl is rounded up
+fn ttl_millis(ttl: Option<Duration>) -> u64 {
+    match ttl {
+        Some(ttl) => (ttl.as_millis() as u64).max(1),
+        None => 0,
+    }
+}
+
+#[inline]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(key = %key, etag = %String::from_utf8_lossy(etag))
+    )
+)]
+fn get_from_redis_through_etag(
+    trace_id: &TraceId,
+    key: &str,
+    etag: &[u8],
+    lease: Duration,
+    conn: &mut dyn redis::ConnectionLike,
+) -> Result<redis::Value, redis::RedisError> {
+    probe_lazy!(
+        ccache,
+        store,
+        trace::Event::new("get_from_redis_through_etag", "start", key)
+            .with_trace_id(trace_id)
+            .as_ptr()
+    );
+
+    // NOTICE HGETALLETAG is in a self build Redis, it works like GET_FROM_REDIS_SCRIPT
+    // see: https://github.com/yfractal/redis/commit/629fbc49a7f6167a6f7980e932e7f3554212b031
     // let result = redis::cmd("HGETALLETAG")
     //     .arg(key.to_string())
     //     .arg(etag.to_string())
     //     .query(conn);
-    let result = Script::new(GET_FROM_REDIS_SCRIPT)
-        .key(key)
-        .arg(etag)
-        .invoke(conn);
+    let lease_ms = lease.as_millis() as u64;
+    let result = GET_COMMAND.with(|command| {
+        let mut packed = command.take();
+        pack_get_command(&mut packed, key, etag, lease_ms);
+        let result = conn.req_packed_command(&packed);
+        command.set(packed);
+        result
+    });
+    let result = match result {
+        // invoke loads the script first
+        Err(e) if e.kind() == redis::ErrorKind::NoScriptError => {
+            let mut invocation = GET_FROM_REDIS.prepare_invoke();
+            invocation.key(key).arg(etag).arg(lease_ms);
+            if lease_ms > 0 {
+                invocation.key(lease_key(key));
+            }
+            invocation.invoke(conn)
+        }
+        result => result,
+    };
+
+    probe_lazy!(
+        ccache,
+        store,
+        trace::Event::new("get_from_redis_through_etag", "end", key)
+            .with_trace_id(trace_id)
+            .as_ptr()
+    );
+
+    result
+}
+
+// Packs EVALSHA of GET_FROM_REDIS into buf, as ScriptInvocation would into a new buffer.
+// The packing itself doesn't allocate once buf has grown to fit.
+// With a lease, the lease hash of key is packed in place too.
+fn pack_get_command(buf: &mut Vec<u8>, key: &str, etag: &[u8], lease_ms: u64) {
+    fn pack_arg(buf: &mut Vec<u8>, arg: &[u8]) {
+        pack_arg_parts(buf, &[arg]);
+    }
+
+    fn pack_arg_parts(buf: &mut Vec<u8>, parts: &[&[u8]]) {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        let _ = write!(buf, "${}\r\n", len);
+        for part in parts {
+            buf.extend_from_slice(part);
+        }
+        buf.extend_from_slice(b"\r\n");
+    }
+
+    let mut lease = [0; 20];
+    let lease_len = {
+        let mut cursor = &mut lease[..];
+        let _ = write!(cursor, "{}", lease_ms);
+        20 - cursor.len()
+    };
+
+    buf.clear();
+    if lease_ms > 0 {
+        buf.extend_from_slice(b"*7\r\n");
+        pack_arg(buf, b"EVALSHA");
+        pack_arg(buf, GET_FROM_REDIS.get_hash().as_bytes());
+        pack_arg(buf, b"2");
+        pack_arg(buf, key.as_bytes());
+        pack_arg_parts(buf, &lease_key_parts(key).map(str::as_bytes));
+    } else {
+        buf.extend_from_slice(b"*6\r\n");
+        pack_arg(buf, b"EVALSHA");
+        pack_arg(buf, GET_FROM_REDIS.get_hash().as_bytes());
+        pack_arg(buf, b"1");
+        pack_arg(buf, key.as_bytes());
+    }
+    pack_arg(buf, etag);
+    pack_arg(buf, &lease[..lease_len]);
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "get_from_redis_through_etag",
+        level = "debug",
+        skip_all,
+        fields(key = %key, etag = %String::from_utf8_lossy(etag))
+    )
+)]
+async fn get_from_redis_through_etag_async<C: redis::aio::ConnectionLike>(
+    trace_id: &TraceId,
+    key: &str,
+    etag: &[u8],
+    lease: Duration,
+    conn: &mut C,
+) -> Result<redis::Value, redis::RedisError> {
+    probe_lazy!(
+        ccache,
+        store,
+        trace::Event::new("get_from_redis_through_etag", "start", key)
+            .with_trace_id(trace_id)
+            .as_ptr()
+    );
+
+    let mut invocation = GET_FROM_REDIS.prepare_invoke();
+    invocation.key(key).arg(etag).arg(lease.as_millis() as u64);
+    if !lease.is_zero() {
+        invocation.key(lease_key(key));
+    }
+    let result = invocation.invoke_async(conn).await;
+
+    probe_lazy!(
+        ccache,
+        store,
+        trace::Event::new("get_from_redis_through_etag", "end", key)
+            .with_trace_id(trace_id)
+            .as_ptr()
+    );
+
+    result
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(keys = keys.len()))
+)]
+fn get_many_from_redis_through_etag(
+    trace_id: &TraceId,
+    keys: &[&str],
+    etags: &[&[u8]],
+    lease: Duration,
+    conn: &mut dyn redis::ConnectionLike,
+) -> Result<Vec<redis::Value>, redis::RedisError> {
+    probe_lazy!(
+        ccache,
+        store,
+        trace::Event::new("get_many_from_redis_through_etag", "start", "")
+            .with_trace_id(trace_id)
+            .as_ptr()
+    );
+
+    let script = Script::new(GET_MANY_FROM_REDIS_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    for (key, etag) in keys.iter().zip(etags) {
+        invocation.key(*key).arg(*etag);
+    }
+    invocation.arg(lease.as_millis() as u64);
+    if !lease.is_zero() {
+        for key in keys {
+            invocation.key(lease_key(key));
+        }
+    }
+    let result = invocation.invoke(conn);
+
+    probe_lazy!(
+        ccache,
+        store,
+        trace::Event::new("get_many_from_redis_through_etag", "end", "")
+            .with_trace_id(trace_id)
+            .as_ptr()
+    );
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate flate2;
+    use super::*;
+    use crate::errors::DecodeError;
+    use crate::errors::EncodeError;
+    use crate::serializable::Serializable;
+    use bincode::{Decode, Encode};
+    use derive::Serializable;
+    use flate2::Compression;
+    use std::io::Write;
+
+    impl<T: Serializable> InMemoryStore<T> {
+        pub fn delete(&self, key: &str) {
+            let mut map = self.map.write_guard(key);
+            self.map.remove(&mut map, key);
+        }
+
+        pub fn update_etag(&self, key: &str, new_etag: &str) {
+            let mut map = self.map.write_guard(key);
+            let data: &mut Arc<DataInner<T>> = map.get_mut(key).unwrap();
+            let val = data.val();
+            let expires_at = data.ttl().map(|ttl| Instant::now() + ttl);
+            // the fake etag is likely older, which insert would refuse
+            self.map.remove(&mut map, key);
+            self.map.insert(
+                &mut map,
+                key,
+                Arc::new(DataInner::new(
+                    new_etag.as_bytes().to_vec(),
+                    val.clone(),
+                    0,
+                    expires_at,
+                )),
+            );
+        }
+    }
+
+    impl<T: PartialEq> PartialEq for GetResult<T> {
+        fn eq(&self, other: &Self) -> bool {
+            match (self, other) {
+                (GetResult::None, GetResult::None) => true,
+                (GetResult::Unchanged(a), GetResult::Unchanged(b)) => a == b,
+                (GetResult::New(a), GetResult::New(b)) => a == b,
+                (GetResult::Unvalidated(a), GetResult::Unvalidated(b)) => a == b,
+                (GetResult::Stale(a, _), GetResult::Stale(b, _)) => a == b,
+                _ => false,
+            }
+        }
+    }
+
+    #[derive(Encode, Decode, Serializable, PartialEq, Debug, Clone)]
+    struct Entity {
+        x: f32,
+        y: f32,
+    }
+
+    #[derive(Encode, Decode, Serializable, PartialEq, Debug, Clone)]
+    struct World(Vec<Entity>);
+
+    struct TestContext<T: Serializable> {
+        in_memory_store: InMemoryStore<T>,
+        redis_conn: redis::Connection,
+        // the keys of a test start with it, so tests running in parallel don't share keys
+        prefix: String,
+    }
+
+    impl<T: Serializable> TestContext<T> {
+        fn key(&self, name: &str) -> String {
+            format!("{}{}", self.prefix, name)
+        }
+    }
+
+    impl<T: Serializable> Drop for TestContext<T> {
+        // deletes the keys of the test, along with their lease and load lock keys
+        fn drop(&mut self) {
+            let keys: Vec<String> = redis::cmd("KEYS")
+                .arg(format!("*{}*", self.prefix))
+                .query(&mut self.redis_conn)
+                .unwrap();
+            if !keys.is_empty() {
+                let _: () = redis::cmd("DEL")
+                    .arg(keys)
+                    .query(&mut self.redis_conn)
+                    .unwrap();
+            }
+        }
+    }
+
+    // a connection to a Redis which is down
+    struct Unreachable;
+
+    impl Unreachable {
+        fn error() -> redis::RedisError {
+            redis::RedisError::from(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+        }
+    }
+
+    impl redis::ConnectionLike for Unreachable {
+        fn req_packed_command(&mut self, _cmd: &[u8]) -> redis::RedisResult<redis::Value> {
+            Err(Self::error())
+        }
+
+        fn req_packed_commands(
+            &mut self,
+            _cmd: &[u8],
+            _offset: usize,
+            _count: usize,
+        ) -> redis::RedisResult<Vec<redis::Value>> {
+            Err(Self::error())
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+
+        fn check_connection(&mut self) -> bool {
+            false
+        }
+
+        fn is_open(&self) -> bool {
+            false
+        }
+    }
+
+    // a connection to a Redis which answers every request with an error
+    struct Answering;
+
+    impl redis::ConnectionLike for Answering {
+        fn req_packed_command(&mut self, _cmd: &[u8]) -> redis::RedisResult<redis::Value> {
+            Err(redis::RedisError::from((
+                redis::ErrorKind::ResponseError,
+                "WRONGTYPE",
+            )))
+        }
+
+        fn req_packed_commands(
+            &mut self,
+            _cmd: &[u8],
+            _offset: usize,
+            _count: usize,
+        ) -> redis::RedisResult<Vec<redis::Value>> {
+            Err(redis::RedisError::from((
+                redis::ErrorKind::ResponseError,
+                "WRONGTYPE",
+            )))
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+
+        fn check_connection(&mut self) -> bool {
+            true
+        }
+
+        fn is_open(&self) -> bool {
+            true
+        }
+    }
+
+    // a connection to a Redis which takes its time to fail: a request signals entered,
+    // then blocks until release is sent to or dropped
+    struct Stalled {
+        entered: std::sync::mpsc::Sender<()>,
+        release: std::sync::mpsc::Receiver<()>,
+    }
+
+    impl Stalled {
+        fn stall(&mut self) -> redis::RedisError {
+            let _ = self.entered.send(());
+            let _ = self.release.recv();
+            Unreachable::error()
+        }
+    }
+
+    impl redis::ConnectionLike for Stalled {
+        fn req_packed_command(&mut self, _cmd: &[u8]) -> redis::RedisResult<redis::Value> {
+            Err(self.stall())
+        }
+
+        fn req_packed_commands(
+            &mut self,
+            _cmd: &[u8],
+            _offset: usize,
+            _count: usize,
+        ) -> redis::RedisResult<Vec<redis::Value>> {
+            Err(self.stall())
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+
+        fn check_connection(&mut self) -> bool {
+            false
+        }
+
+        fn is_open(&self) -> bool {
+            true
+        }
+    }
+
+    // an async connection to a Redis which never answers
+    struct Hanging;
+
+    impl redis::aio::ConnectionLike for Hanging {
+        fn req_packed_command<'a>(
+            &'a mut self,
+            _cmd: &'a redis::Cmd,
+        ) -> redis::RedisFuture<'a, redis::Value> {
+            Box::pin(std::future::pending())
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            _cmd: &'a redis::Pipeline,
+            _offset: usize,
+            _count: usize,
+        ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+            Box::pin(std::future::pending())
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    fn setup<T: Serializable>(test: &str) -> TestContext<T> {
+        let in_memory_store = InMemoryStore::new();
+
+        // Connect to Redis
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let redis_conn = client.get_connection().unwrap();
+
+        TestContext {
+            in_memory_store,
+            redis_conn,
+            prefix: format!("ccache-test:{}:", test),
+        }
+    }
+
+    // polls done until it holds, for what a test can't wait on directly
+    fn wait_until(mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_get() {
+        let mut ctx = setup("get");
+        let key = &ctx.key("some-key");
+        let in_memory_store = &mut ctx.in_memory_store;
+        let val = World(vec![Entity { x: 0.0, y: 4.0 }, Entity { x: 10.0, y: 20.5 }]);
+        let _ = in_memory_store.insert(key, val, &mut ctx.redis_conn);
+
+        let result = in_memory_store
+            .get(key, &mut ctx.redis_conn)
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.0.first().unwrap().x, 0.0);
+        assert_eq!(result.0.first().unwrap().y, 4.0);
+
+        assert_eq!(result.0.get(1).unwrap().x, 10.0);
+        assert_eq!(result.0.get(1).unwrap().y, 20.5);
+    }
+
+    #[test]
+    fn test_get_none_exist() {
+        let mut ctx = setup::<World>("get_none_exist");
+        let in_memory_store = &ctx.in_memory_store;
+        let result = in_memory_store
+            .get("non-exist-key", &mut ctx.redis_conn)
+            .unwrap();
+
+        assert_eq!(result, GetResult::None);
+    }
+
+    #[test]
+    fn test_get_local_miss_remote_hit() {
+        let mut ctx = setup("get_local_miss_remote_hit");
+        let key = &ctx.key("some-key");
+        let in_memory_store = &mut ctx.in_memory_store;
+
+        // insert one and delete from local
+        in_memory_store
+            .insert(key, Entity { x: 0.0, y: 4.0 }, &mut ctx.redis_conn)
+            .unwrap();
+        in_memory_store.delete(key);
+
+        let result = in_memory_store
+            .get(key, &mut ctx.redis_conn)
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.x, 0.0);
+        assert_eq!(result.y, 4.0);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut ctx = setup("stats");
+        let key = &ctx.key("stats-key");
+        let in_memory_store = &mut ctx.in_memory_store;
+
+        in_memory_store
+            .insert(key, Entity { x: 0.0, y: 4.0 }, &mut ctx.redis_conn)
+            .unwrap();
+        let stats = in_memory_store.stats();
+        assert_eq!(stats.entries, 1);
+        assert!(stats.entry_bytes > key.len() as u64);
+
+        in_memory_store.get(key, &mut ctx.redis_conn).unwrap();
+        in_memory_store.delete(key);
+        in_memory_store.get(key, &mut ctx.redis_conn).unwrap();
+        in_memory_store
+            .get("non-exist-key", &mut ctx.redis_conn)
+            .unwrap();
+
+        let stats = in_memory_store.stats();
+        assert_eq!(stats.unchanged, 1);
+        assert_eq!(stats.new, 1);
+        assert_eq!(stats.none, 1);
+        assert_eq!(stats.errors, 0);
+        assert_eq!(stats.decode_latency.count, 1);
+        assert!(stats.bytes_fetched > 0);
+        assert_eq!(stats.entries, 1);
+    }
+
+    #[test]
+    fn test_local_cached_remote_does_not_exist() {
+        let mut ctx = setup("local_cached_remote_does_not_exist");
+        let key = &ctx.key("some-key");
+        let in_memory_store = &mut ctx.in_memory_store;
+
+        // insert one and delete redis
+        in_memory_store
+            .insert(key, Entity { x: 0.0, y: 4.0 }, &mut ctx.redis_conn)
+            .unwrap();
+        redis::cmd("del")
+            .arg(key)
+            .query::<bool>(&mut ctx.redis_conn)
+            .unwrap();
+
+        let result = in_memory_store.get(key, &mut ctx.redis_conn).unwrap();
+
+        assert_eq!(result, GetResult::None);
+        // the stale copy is dropped, the next get is a local miss
+        assert!(in_memory_store.local_entry(key).is_none());
+        assert_eq!(in_memory_store.stats().removed_remote, 1);
+
+        let result = in_memory_store.get(key, &mut ctx.redis_conn).unwrap();
+        assert_eq!(result, GetResult::None);
+        assert_eq!(in_memory_store.stats().removed_remote, 1);
+    }
+
+    #[test]
+    fn test_local_miss() {
+        let mut ctx = setup("local_miss");
+        let key = &ctx.key("some-key");
+        let in_memory_store = &mut ctx.in_memory_store;
+
+        // insert one entity and update etag
+        in_memory_store
+            .insert(key, Entity { x: 0.0, y: 4.0 }, &mut ctx.redis_conn)
+            .unwrap();
+        in_memory_store.update_etag(key, "abc");
+
+        let result = in_memory_store
+            .get(key, &mut ctx.redis_conn)
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.x, 0.0);
+        assert_eq!(result.y, 4.0);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut ctx = setup("remove");
+        let key = &ctx.key("some-key");
+        let in_memory_store = &ctx.in_memory_store;
+        let other_store = InMemoryStore::<Entity>::new();
+
+        in_memory_store
+            .insert(key, Entity { x: 0.0, y: 4.0 }, &mut ctx.redis_conn)
+            .unwrap();
+        other_store.get(key, &mut ctx.redis_conn).unwrap();
+
+        assert!(in_memory_store.remove(key, &mut ctx.redis_conn).unwrap());
+        assert!(in_memory_store.map.read_guard(key).get(key).is_none());
+
+        let exists: bool = redis::cmd("EXISTS")
+            .arg(key)
+            .query(&mut ctx.redis_conn)
+            .unwrap();
+        assert!(!exists);
+
+        // a process holding the old value sees the removal on its next validation
+        let result = other_store.get(key, &mut ctx.redis_conn).unwrap();
+        assert_eq!(result, GetResult::None);
+
+        assert!(!in_memory_store.remove(key, &mut ctx.redis_conn).unwrap());
+    }
+
+    #[test]
+    fn test_evicted_key_falls_back_to_local_miss() {
+        let mut ctx = setup("evicted_key_falls_back_to_local_miss");
+        ctx.in_memory_store = InMemoryStore::new().with_capacity(Capacity::Entries(128));
+        let keys: Vec<String> = (0..512).map(|i| ctx.key(&format!("key-{}", i))).collect();
+        let in_memory_store = &ctx.in_memory_store;
+
+        for (i, key) in keys.iter().enumerate() {
+            in_memory_store
+                .insert(
+                    key,
+                    Entity {
+                        x: i as f32,
+                        y: 0.0,
+                    },
+                    &mut ctx.redis_conn,
+                )
+                .unwrap();
+        }
+
+        let mut reloaded = 0;
+        for (i, key) in keys.iter().enumerate() {
+            match in_memory_store.get(key, &mut ctx.redis_conn).unwrap() {
+                GetResult::New(val) => {
+                    reloaded += 1;
+                    assert_eq!(val.x, i as f32);
+                }
+                GetResult::Unchanged(val) => assert_eq!(val.x, i as f32),
+                other => panic!("{} should exist, got {:?}", key, other),
+            }
+        }
+
+        assert!(reloaded >= 512 - 128);
+    }
+
+    #[test]
+    fn test_insert_with_ttl() {
+        let mut ctx = setup("insert_with_ttl");
+        let key = &ctx.key("some-key");
+        let in_memory_store = &ctx.in_memory_store;
+        let other_store = InMemoryStore::<Entity>::new();
+
+        in_memory_store
+            .insert_with_ttl(
+                key,
+                Entity { x: 0.0, y: 4.0 },
+                Duration::from_millis(300),
+                &mut ctx.redis_conn,
+            )
+            .unwrap();
+
+        let pttl: i64 = redis::cmd("PTTL")
+            .arg(key)
+            .query(&mut ctx.redis_conn)
+            .unwrap();
+        assert!(pttl > 0 && pttl <= 300);
+        assert!(in_memory_store.ttl(key).unwrap() <= Duration::from_millis(300));
+
+        // the deadline is mirrored to other processes
+        other_store.get(key, &mut ctx.redis_conn).unwrap();
+        assert!(other_store.ttl(key).unwrap() <= Duration::from_millis(300));
+
+        wait_until(|| {
+            !redis::cmd("EXISTS")
+                .arg(key)
+                .query::<bool>(&mut ctx.redis_conn)
+                .unwrap()
+        });
+
+        // the local deadlines are no later than the one in Redis
+        assert_eq!(in_memory_store.ttl(key), None);
+        let result = other_store.get(key, &mut ctx.redis_conn).unwrap();
+        assert_eq!(result, GetResult::None);
+        assert!(other_store.map.read_guard(key).is_empty());
+    }
+
+    #[test]
+    fn test_insert_clears_ttl() {
+        let mut ctx = setup("insert_clears_ttl");
+        let key = &ctx.key("some-key");
+        let in_memory_store = &ctx.in_memory_store;
+
+        in_memory_store
+            .insert_with_ttl(
+                key,
+                Entity { x: 0.0, y: 4.0 },
+                Duration::from_secs(60),
+                &mut ctx.redis_conn,
+            )
+            .unwrap();
+        in_memory_store
+            .insert(key, Entity { x: 1.0, y: 4.0 }, &mut ctx.redis_conn)
+            .unwrap();
+
+        let pttl: i64 = redis::cmd("PTTL")
+            .arg(key)
+            .query(&mut ctx.redis_conn)
+            .unwrap();
+        assert_eq!(pttl, -1);
+        assert_eq!(in_memory_store.ttl(key), None);
+    }
+
+    #[test]
+    fn test_get_many() {
+        let mut ctx = setup("get_many");
+        let key0 = &ctx.key("key-0");
+        let key1 = &ctx.key("key-1");
+        let key2 = &ctx.key("key-2");
+        let in_memory_store = &ctx.in_memory_store;
+        let other_store = InMemoryStore::<Entity>::new();
+
+        for (i, key) in [key0, key1, key2].iter().enumerate() {
+            in_memory_store
+                .insert(
+                    key,
+                    Entity {
+                        x: i as f32,
+                        y: 0.0,
+                    },
+                    &mut ctx.redis_conn,
+                )
+                .unwrap();
+        }
+        // key-1 changes behind in_memory_store's back
+        other_store
+            .insert(key1, Entity { x: 10.0, y: 0.0 }, &mut ctx.redis_conn)
+            .unwrap();
+
+        let results = in_memory_store
+            .get_many(
+                &[key0, key1, "non-exist-key", key2, key0],
+                &mut ctx.redis_conn,
+            )
+            .unwrap();
+
+        assert_eq!(
+            results,
+            vec![
+                GetResult::Unchanged(Arc::new(Entity { x: 0.0, y: 0.0 })),
+                GetResult::New(Arc::new(Entity { x: 10.0, y: 0.0 })),
+                GetResult::None,
+                GetResult::Unchanged(Arc::new(Entity { x: 2.0, y: 0.0 })),
+                GetResult::Unchanged(Arc::new(Entity { x: 0.0, y: 0.0 })),
+            ]
+        );
+
+        // the new value of key-1 is cached
+        let result = in_memory_store.get(key1, &mut ctx.redis_conn).unwrap();
+        assert_eq!(
+            result,
+            GetResult::Unchanged(Arc::new(Entity { x: 10.0, y: 0.0 }))
+        );
+    }
+
+    #[test]
+    fn test_insert_many() {
+        let mut ctx = setup("insert_many");
+        let key0 = &ctx.key("key-0");
+        let key1 = &ctx.key("key-1");
+        let in_memory_store = &ctx.in_memory_store;
+        let other_store = InMemoryStore::<Entity>::new();
 
-    probe!(
-        ccache,
-        store,
-        trace::Event::new("get_from_redis_through_etag", "end", key, &uuid.to_string()).as_ptr()
-    );
+        in_memory_store
+            .insert(key0, Entity { x: 0.0, y: 0.0 }, &mut ctx.redis_conn)
+            .unwrap();
 
-    result
-}
+        let etags = in_memory_store
+            .insert_many(
+                vec![
+                    (key0, Entity { x: 1.0, y: 0.0 }),
+                    (key1, Entity { x: 2.0, y: 0.0 }),
+                ],
+                &mut ctx.redis_conn,
+            )
+            .unwrap();
 
-#[cfg(test)]
-mod tests {
-    extern crate flate2;
-    use super::*;
-    use crate::errors::DecodeError;
-    use crate::errors::EncodeError;
-    use crate::serializable::Serializable;
-    use bincode::{Decode, Encode};
-    use derive::Serializable;
-    use flate2::Compression;
-    use std::io::Write;
+        for (key, etag) in [key0, key1].iter().zip(etags) {
+            let redis_etag: Vec<u8> = redis::cmd("HGET")
+                .arg(key)
+                .arg("etag")
+                .query(&mut ctx.redis_conn)
+                .unwrap();
+            assert_eq!(redis_etag, etag);
+        }
 
-    impl<T: Serializable> InMemoryStore<T> {
-        pub fn delete(&self, key: &str) {
-            let mut map = self.map.write_guard(&key.to_string());
-            map.remove(key);
+        // cached locally by the writer
+        let results = in_memory_store
+            .get_many(&[key0, key1], &mut ctx.redis_conn)
+            .unwrap();
+        assert_eq!(
+            results,
+            vec![
+                GetResult::Unchanged(Arc::new(Entity { x: 1.0, y: 0.0 })),
+                GetResult::Unchanged(Arc::new(Entity { x: 2.0, y: 0.0 })),
+            ]
+        );
+
+        let results = other_store
+            .get_many(&[key0, key1], &mut ctx.redis_conn)
+            .unwrap();
+        assert_eq!(
+            results,
+            vec![
+                GetResult::New(Arc::new(Entity { x: 1.0, y: 0.0 })),
+                GetResult::New(Arc::new(Entity { x: 2.0, y: 0.0 })),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_insert_if_match() {
+        let mut ctx = setup("insert_if_match");
+        let key0 = &ctx.key("key-0");
+        let in_memory_store = &ctx.in_memory_store;
+        let other_store = InMemoryStore::<Entity>::new();
+
+        // None expects a missing key
+        let etag = in_memory_store
+            .insert_if_match(key0, Entity { x: 1.0, y: 0.0 }, None, &mut ctx.redis_conn)
+            .unwrap();
+
+        let (val, read_etag) = other_store
+            .get_with_etag(key0, &mut ctx.redis_conn)
+            .unwrap()
+            .unwrap();
+        assert_eq!(*val, Entity { x: 1.0, y: 0.0 });
+        assert_eq!(read_etag, etag);
+
+        let new_etag = other_store
+            .insert_if_match(
+                key0,
+                Entity { x: 2.0, y: 0.0 },
+                Some(&read_etag),
+                &mut ctx.redis_conn,
+            )
+            .unwrap();
+
+        // in_memory_store still holds etag, which is stale now
+        match in_memory_store.insert_if_match(
+            key0,
+            Entity { x: 3.0, y: 0.0 },
+            Some(&etag),
+            &mut ctx.redis_conn,
+        ) {
+            Err(Error::Conflict { current_etag }) => {
+                assert_eq!(current_etag, Some(new_etag.clone()))
+            }
+            other => panic!("expected a conflict, got {:?}", other),
         }
 
-        pub fn update_etag(&self, key: &str, new_etag: &str) {
-            let mut map = self.map.write_guard(&key.to_string());
-            let data: &mut Arc<DataInner<T>> = map.get_mut(key).unwrap();
-            let val = data.val();
-            map.insert(
-                key.to_string(),
-                Arc::new(DataInner(new_etag.as_bytes().to_vec(), val.clone())),
-            );
+        match in_memory_store.insert_if_match(
+            key0,
+            Entity { x: 3.0, y: 0.0 },
+            None,
+            &mut ctx.redis_conn,
+        ) {
+            Err(Error::Conflict { current_etag }) => assert_eq!(current_etag, Some(new_etag)),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+
+        assert_eq!(
+            in_memory_store.get(key0, &mut ctx.redis_conn).unwrap(),
+            GetResult::New(Arc::new(Entity { x: 2.0, y: 0.0 }))
+        );
+
+        other_store.remove(key0, &mut ctx.redis_conn).unwrap();
+        match in_memory_store.insert_if_match(
+            key0,
+            Entity { x: 3.0, y: 0.0 },
+            Some(&etag),
+            &mut ctx.redis_conn,
+        ) {
+            Err(Error::Conflict { current_etag }) => assert_eq!(current_etag, None),
+            other => panic!("expected a conflict, got {:?}", other),
         }
     }
 
-    impl<T: PartialEq> PartialEq for GetResult<T> {
-        fn eq(&self, other: &Self) -> bool {
-            match (self, other) {
-                (GetResult::None, GetResult::None) => true,
-                (GetResult::Unchanged(a), GetResult::Unchanged(b)) => a == b,
-                (GetResult::New(a), GetResult::New(b)) => a == b,
-                _ => false,
-            }
+    #[test]
+    fn test_update() {
+        let mut ctx = setup("update");
+        let key0 = &ctx.key("key-0");
+        let key1 = &ctx.key("key-1");
+        ctx.in_memory_store
+            .insert(key0, Entity { x: 0.0, y: 0.0 }, &mut ctx.redis_conn)
+            .unwrap();
+
+        // every thread has its own store, so the increments race in Redis
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let key0 = key0.clone();
+                std::thread::spawn(move || {
+                    let store = InMemoryStore::<Entity>::new().with_max_update_retries(1000);
+                    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+                    let mut conn = client.get_connection().unwrap();
+
+                    for _ in 0..25 {
+                        store
+                            .update(
+                                &key0,
+                                |old| {
+                                    let old = old.unwrap();
+                                    Entity {
+                                        x: old.x + 1.0,
+                                        y: old.y,
+                                    }
+                                },
+                                &mut conn,
+                            )
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
         }
+
+        assert_eq!(
+            ctx.in_memory_store
+                .get(key0, &mut ctx.redis_conn)
+                .unwrap()
+                .unwrap(),
+            Arc::new(Entity { x: 100.0, y: 0.0 })
+        );
+
+        // a missing key is passed as None
+        ctx.in_memory_store
+            .update(
+                key1,
+                |old| {
+                    assert!(old.is_none());
+                    Entity { x: 1.0, y: 1.0 }
+                },
+                &mut ctx.redis_conn,
+            )
+            .unwrap();
     }
 
-    #[derive(Encode, Decode, Serializable, PartialEq, Debug, Clone)]
-    struct Entity {
-        x: f32,
-        y: f32,
+    #[test]
+    fn test_update_keeps_ttl() {
+        let mut ctx = setup("update_keeps_ttl");
+        let key = &ctx.key("some-key");
+        let in_memory_store = &ctx.in_memory_store;
+
+        in_memory_store
+            .insert_with_ttl(
+                key,
+                Entity { x: 0.0, y: 4.0 },
+                Duration::from_secs(60),
+                &mut ctx.redis_conn,
+            )
+            .unwrap();
+        in_memory_store
+            .update(
+                key,
+                |old| Entity {
+                    x: old.unwrap().x + 1.0,
+                    y: 4.0,
+                },
+                &mut ctx.redis_conn,
+            )
+            .unwrap();
+
+        let pttl: i64 = redis::cmd("PTTL")
+            .arg(key)
+            .query(&mut ctx.redis_conn)
+            .unwrap();
+        assert!(pttl > 0 && pttl <= 60_000);
+        assert!(in_memory_store.ttl(key).unwrap() <= Duration::from_secs(60));
     }
 
-    #[derive(Encode, Decode, Serializable, PartialEq, Debug, Clone)]
-    struct World(Vec<Entity>);
+    #[test]
+    fn test_update_gives_up_after_max_retries() {
+        let mut ctx = setup::<Entity>("update_gives_up_after_max_retries");
+        let key0 = &ctx.key("key-0");
+        let other_store = InMemoryStore::<Entity>::new();
+        let store = InMemoryStore::<Entity>::new().with_max_update_retries(2);
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let mut conn = client.get_connection().unwrap();
+
+        let mut calls = 0;
+        let result = store.update(
+            key0,
+            |_| {
+                calls += 1;
+                // someone else always writes first
+                other_store
+                    .insert(key0, Entity { x: 0.0, y: 0.0 }, &mut conn)
+                    .unwrap();
+                Entity { x: 1.0, y: 0.0 }
+            },
+            &mut ctx.redis_conn,
+        );
 
-    struct TestContext<T: Serializable> {
-        in_memory_store: InMemoryStore<T>,
-        redis_conn: redis::Connection,
+        assert!(matches!(result, Err(Error::Conflict { .. })));
+        assert_eq!(calls, 3);
     }
 
-    impl<T: Serializable> Drop for TestContext<T> {
-        fn drop(&mut self) {
-            let _: () = redis::cmd("FLUSHDB").query(&mut self.redis_conn).unwrap();
-        }
+    #[test]
+    fn test_get_with_max_staleness() {
+        let mut ctx = setup("get_with_max_staleness");
+        let key0 = &ctx.key("key-0");
+        let in_memory_store = &ctx.in_memory_store;
+        let other_store = InMemoryStore::<Entity>::new();
+        let options = ReadOptions {
+            max_staleness: Duration::from_millis(200),
+        };
+
+        in_memory_store
+            .insert(key0, Entity { x: 1.0, y: 0.0 }, &mut ctx.redis_conn)
+            .unwrap();
+        other_store
+            .insert(key0, Entity { x: 2.0, y: 0.0 }, &mut ctx.redis_conn)
+            .unwrap();
+
+        // the local copy was written just now, so it's served without asking Redis
+        assert_eq!(
+            in_memory_store
+                .get_with_options(key0, &options, &mut ctx.redis_conn)
+                .unwrap(),
+            GetResult::Unvalidated(Arc::new(Entity { x: 1.0, y: 0.0 }))
+        );
+
+        std::thread::sleep(Duration::from_millis(250));
+        assert_eq!(
+            in_memory_store
+                .get_with_options(key0, &options, &mut ctx.redis_conn)
+                .unwrap(),
+            GetResult::New(Arc::new(Entity { x: 2.0, y: 0.0 }))
+        );
+
+        // a plain get always validates
+        assert_eq!(
+            in_memory_store.get(key0, &mut ctx.redis_conn).unwrap(),
+            GetResult::Unchanged(Arc::new(Entity { x: 2.0, y: 0.0 }))
+        );
+        // and so restarts the window
+        assert_eq!(
+            in_memory_store
+                .get_with_options(key0, &options, &mut ctx.redis_conn)
+                .unwrap(),
+            GetResult::Unvalidated(Arc::new(Entity { x: 2.0, y: 0.0 }))
+        );
     }
 
-    fn setup<T: Serializable>() -> TestContext<T> {
-        let in_memory_store = InMemoryStore::new();
+    #[test]
+    fn test_read_lease() {
+        let mut ctx = setup::<Entity>("read_lease");
+        let key0 = &ctx.key("key-0");
+        let reader = InMemoryStore::<Entity>::new().with_read_lease(Duration::from_millis(300));
 
-        // Connect to Redis
-        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
-        let redis_conn = client.get_connection().unwrap();
+        ctx.in_memory_store
+            .insert(key0, Entity { x: 1.0, y: 0.0 }, &mut ctx.redis_conn)
+            .unwrap();
+        assert_eq!(
+            reader.get(key0, &mut ctx.redis_conn).unwrap(),
+            GetResult::New(Arc::new(Entity { x: 1.0, y: 0.0 }))
+        );
 
-        TestContext {
-            in_memory_store,
-            redis_conn,
-        }
+        // the lease is kept off the key, granting it isn't a write of the key
+        let fields: i64 = redis::cmd("HLEN")
+            .arg(key0)
+            .query(&mut ctx.redis_conn)
+            .unwrap();
+        assert_eq!(fields, 2);
+        let pttl: i64 = redis::cmd("PTTL")
+            .arg(lease_key(key0))
+            .query(&mut ctx.redis_conn)
+            .unwrap();
+        assert!(pttl > 0 && pttl <= 300);
+
+        // only writers with leases of their own wait for them
+        let writer_key = key0.clone();
+        let writer = std::thread::spawn(move || {
+            let store = InMemoryStore::<Entity>::new().with_read_lease(Duration::from_millis(300));
+            let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+            let mut conn = client.get_connection().unwrap();
+
+            let started = Instant::now();
+            store
+                .insert(&writer_key, Entity { x: 2.0, y: 0.0 }, &mut conn)
+                .unwrap();
+            started.elapsed()
+        });
+
+        // the write waits for the lease, fencing off new ones, meanwhile the reader serves its copy
+        wait_until(|| {
+            redis::cmd("HEXISTS")
+                .arg(lease_key(key0))
+                .arg("fence")
+                .query(&mut ctx.redis_conn)
+                .unwrap()
+        });
+        assert_eq!(
+            reader.get(key0, &mut ctx.redis_conn).unwrap(),
+            GetResult::Unchanged(Arc::new(Entity { x: 1.0, y: 0.0 }))
+        );
+
+        let waited = writer.join().unwrap();
+        assert!(waited >= Duration::from_millis(200));
+
+        // the local lease ends no later than the one in Redis
+        assert_eq!(
+            reader.get(key0, &mut ctx.redis_conn).unwrap(),
+            GetResult::New(Arc::new(Entity { x: 2.0, y: 0.0 }))
+        );
     }
 
     #[test]
-    fn test_get() {
-        let mut ctx = setup();
-        let in_memory_store = &mut ctx.in_memory_store;
-        let key = "some-key";
-        let val = World(vec![Entity { x: 0.0, y: 4.0 }, Entity { x: 10.0, y: 20.5 }]);
-        let _ = in_memory_store.insert(key, val, &mut ctx.redis_conn);
+    fn test_tracking() {
+        let mut ctx = setup::<Entity>("tracking");
+        let key0 = &ctx.key("key-0");
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let reader = InMemoryStore::<Entity>::new()
+            .with_tracking(client, &[&ctx.prefix])
+            .unwrap();
+        wait_until(|| reader.is_tracking());
 
-        let result = in_memory_store
-            .get(key, &mut ctx.redis_conn)
-            .unwrap()
+        let seq = reader.tracked_seq(key0);
+        ctx.in_memory_store
+            .insert(key0, Entity { x: 1.0, y: 0.0 }, &mut ctx.redis_conn)
             .unwrap();
-        assert_eq!(result.0.first().unwrap().x, 0.0);
-        assert_eq!(result.0.first().unwrap().y, 4.0);
+        // let the invalidation of the insert arrive
+        wait_until(|| reader.tracked_seq(key0) != seq);
 
-        assert_eq!(result.0.get(1).unwrap().x, 10.0);
-        assert_eq!(result.0.get(1).unwrap().y, 20.5);
+        assert_eq!(
+            reader.get(key0, &mut ctx.redis_conn).unwrap(),
+            GetResult::New(Arc::new(Entity { x: 1.0, y: 0.0 }))
+        );
+        let entry = reader.local_entry(key0).unwrap();
+        assert!(reader.is_fresh(key0, &entry));
+
+        ctx.in_memory_store
+            .insert(key0, Entity { x: 2.0, y: 0.0 }, &mut ctx.redis_conn)
+            .unwrap();
+
+        // dirty once the invalidation arrives, so get validates again
+        wait_until(|| !reader.is_fresh(key0, &entry));
+        assert_eq!(
+            reader.get(key0, &mut ctx.redis_conn).unwrap(),
+            GetResult::New(Arc::new(Entity { x: 2.0, y: 0.0 }))
+        );
+        assert!(reader.is_fresh(key0, &reader.local_entry(key0).unwrap()));
     }
 
     #[test]
-    fn test_get_none_exist() {
-        let mut ctx = setup::<World>();
-        let in_memory_store = &ctx.in_memory_store;
-        let result = in_memory_store
-            .get("non-exist-key", &mut ctx.redis_conn)
+    fn test_corrupt_payload_is_an_error() {
+        let mut ctx = setup::<Entity>("corrupt_payload_is_an_error");
+        let key0 = &ctx.key("key-0");
+
+        let _: () = redis::cmd("HSET")
+            .arg(key0)
+            .arg("val")
+            .arg("not a payload")
+            .arg("etag")
+            .arg("1700000000000000")
+            .query(&mut ctx.redis_conn)
             .unwrap();
 
-        assert_eq!(result, GetResult::None);
+        match ctx.in_memory_store.get(key0, &mut ctx.redis_conn) {
+            Err(Error::Decode(_)) => {}
+            other => panic!("expected a decode error, got {:?}", other),
+        }
+        // and it wasn't cached
+        assert!(ctx.in_memory_store.local_entry(key0).is_none());
+
+        ctx.in_memory_store
+            .insert(key0, Entity { x: 1.0, y: 0.0 }, &mut ctx.redis_conn)
+            .unwrap();
+        assert_eq!(
+            ctx.in_memory_store.get(key0, &mut ctx.redis_conn).unwrap(),
+            GetResult::Unchanged(Arc::new(Entity { x: 1.0, y: 0.0 }))
+        );
     }
 
     #[test]
-    fn test_get_local_miss_remote_hit() {
-        let mut ctx = setup();
-        let in_memory_store = &mut ctx.in_memory_store;
+    fn test_stale_if_error() {
+        let mut ctx = setup::<Entity>("stale_if_error");
+        let key0 = &ctx.key("key-0");
+        let store = InMemoryStore::<Entity>::new().with_stale_if_error(Duration::from_millis(200));
+
+        // no local copy to fall back to
+        assert!(matches!(
+            store.get(key0, &mut Unreachable),
+            Err(Error::Redis(_))
+        ));
+
+        store
+            .insert(key0, Entity { x: 1.0, y: 0.0 }, &mut ctx.redis_conn)
+            .unwrap();
+        match store.get(key0, &mut Unreachable) {
+            Ok(GetResult::Stale(val, Error::Redis(_))) => {
+                assert_eq!(*val, Entity { x: 1.0, y: 0.0 })
+            }
+            other => panic!("expected a stale value, got {:?}", other),
+        }
 
-        // insert one and delete from local
-        in_memory_store
-            .insert("some-key", Entity { x: 0.0, y: 4.0 }, &mut ctx.redis_conn)
+        // Redis answered, so its error is returned
+        assert!(matches!(
+            store.get(key0, &mut Answering),
+            Err(Error::Redis(_))
+        ));
+
+        // too old
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(matches!(
+            store.get(key0, &mut Unreachable),
+            Err(Error::Redis(_))
+        ));
+
+        // off by default
+        ctx.in_memory_store
+            .insert(key0, Entity { x: 1.0, y: 0.0 }, &mut ctx.redis_conn)
             .unwrap();
-        in_memory_store.delete("some-key");
+        assert!(matches!(
+            ctx.in_memory_store.get(key0, &mut Unreachable),
+            Err(Error::Redis(_))
+        ));
+    }
 
-        let result = in_memory_store
-            .get("some-key", &mut ctx.redis_conn)
-            .unwrap()
+    #[test]
+    fn test_circuit_breaker() {
+        let mut ctx = setup::<Entity>("circuit_breaker");
+        let key0 = &ctx.key("key-0");
+        let key1 = &ctx.key("key-1");
+        let store = InMemoryStore::<Entity>::new()
+            .with_circuit_breaker(2, Duration::from_millis(100))
+            .with_stale_if_error(Duration::from_secs(60));
+
+        store
+            .insert(key0, Entity { x: 1.0, y: 0.0 }, &mut ctx.redis_conn)
             .unwrap();
-        assert_eq!(result.x, 0.0);
-        assert_eq!(result.y, 4.0);
+
+        for _ in 0..2 {
+            match store.get(key0, &mut Unreachable) {
+                Ok(GetResult::Stale(_, Error::Redis(_))) => {}
+                other => panic!("expected a stale value, got {:?}", other),
+            }
+        }
+
+        // open, even a healthy connection isn't used
+        match store.get(key0, &mut ctx.redis_conn) {
+            Ok(GetResult::Stale(_, Error::CircuitOpen)) => {}
+            other => panic!("expected a stale value, got {:?}", other),
+        }
+        assert!(matches!(
+            store.get(key1, &mut ctx.redis_conn),
+            Err(Error::CircuitOpen)
+        ));
+
+        // the probe succeeds and closes it
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(
+            store.get(key0, &mut ctx.redis_conn).unwrap(),
+            GetResult::Unchanged(Arc::new(Entity { x: 1.0, y: 0.0 }))
+        );
+        assert_eq!(
+            store.get(key1, &mut ctx.redis_conn).unwrap(),
+            GetResult::None
+        );
+    }
+
+    #[async_std::test]
+    async fn test_dropped_probe_opens_the_breaker_again() {
+        let store =
+            InMemoryStore::<Entity>::new().with_circuit_breaker(1, Duration::from_millis(50));
+
+        assert!(matches!(
+            store.get("key-0", &mut Unreachable),
+            Err(Error::Redis(_))
+        ));
+        async_std::task::sleep(Duration::from_millis(60)).await;
+
+        // the probe's future is dropped while it waits for Redis
+        let mut conn = Hanging;
+        let probe = store.get_async("key-0", &mut conn);
+        let timeout = async_std::future::timeout(Duration::from_millis(20), probe);
+        assert!(timeout.await.is_err());
+
+        // so the breaker is open rather than stuck half open
+        assert!(matches!(
+            store.get("key-0", &mut Unreachable),
+            Err(Error::CircuitOpen)
+        ));
+        async_std::task::sleep(Duration::from_millis(60)).await;
+        assert!(matches!(
+            store.get("key-0", &mut Unreachable),
+            Err(Error::Redis(_))
+        ));
     }
 
     #[test]
-    fn test_local_cached_remote_does_not_exist() {
-        let mut ctx = setup();
-        let in_memory_store = &mut ctx.in_memory_store;
+    fn test_slow_redis_does_not_block_the_shard() {
+        let store = &InMemoryStore::<Entity>::new();
+        let (entered_tx, entered) = std::sync::mpsc::channel();
+        let mut releases = Vec::new();
+        let mut stalled = || {
+            let (release_tx, release) = std::sync::mpsc::channel();
+            releases.push(release_tx);
+            Stalled {
+                entered: entered_tx.clone(),
+                release,
+            }
+        };
+        let (mut get_conn, mut insert_conn, mut remove_conn) = (stalled(), stalled(), stalled());
+
+        std::thread::scope(|s| {
+            s.spawn(move || assert!(store.get("key-0", &mut get_conn).is_err()));
+            s.spawn(move || {
+                assert!(store
+                    .insert("key-0", Entity { x: 1.0, y: 0.0 }, &mut insert_conn)
+                    .is_err())
+            });
+            s.spawn(move || assert!(store.remove("key-0", &mut remove_conn).is_err()));
+
+            for _ in 0..3 {
+                entered.recv_timeout(Duration::from_secs(5)).unwrap();
+            }
 
-        // insert one and delete redis
-        in_memory_store
-            .insert("some-key", Entity { x: 0.0, y: 4.0 }, &mut ctx.redis_conn)
+            // while all three are blocked in Redis, the shard of key-0 is free
+            let (locked_tx, locked) = std::sync::mpsc::channel();
+            s.spawn(move || {
+                drop(store.map.write_guard("key-0"));
+                let _ = locked_tx.send(());
+            });
+            let free = locked.recv_timeout(Duration::from_secs(5)).is_ok();
+
+            drop(releases);
+            assert!(free);
+        });
+    }
+
+    #[test]
+    fn test_pack_get_command() {
+        let mut packed = Vec::new();
+        pack_get_command(&mut packed, "key-0", b"1700000000000001", 300);
+
+        let expected = redis::cmd("EVALSHA")
+            .arg(GET_FROM_REDIS.get_hash())
+            .arg(2)
+            .arg("key-0")
+            .arg("ccache:lease:{key-0}")
+            .arg(b"1700000000000001")
+            .arg(300)
+            .get_packed_command();
+        assert_eq!(packed, expected);
+
+        // the buffer is reused, without a lease there's no lease hash
+        let capacity = packed.capacity();
+        pack_get_command(&mut packed, "key-1", b"-1", 0);
+        assert_eq!(packed.capacity(), capacity);
+        let expected = redis::cmd("EVALSHA")
+            .arg(GET_FROM_REDIS.get_hash())
+            .arg(1)
+            .arg("key-1")
+            .arg(b"-1")
+            .arg(0)
+            .get_packed_command();
+        assert_eq!(packed, expected);
+    }
+
+    #[test]
+    fn test_lease_key() {
+        assert_eq!(lease_key("key-0"), "ccache:lease:{key-0}");
+        // the hash tag of the key is kept, so the lease hash is in the key's slot
+        assert_eq!(lease_key("user:{42}:name"), "ccache:lease:user:{42}:name");
+        // an empty hash tag isn't one
+        assert_eq!(lease_key("user:{}:name"), "ccache:lease:{user:{}:name}");
+    }
+
+    fn is_load_locked(redis_conn: &mut redis::Connection, key: &str) -> bool {
+        redis::cmd("EXISTS")
+            .arg(format!("ccache:load:{}", key))
+            .query(redis_conn)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_get_or_load() {
+        let mut ctx = setup::<Entity>("get_or_load");
+        let key0 = &ctx.key("key-0");
+        // two processes
+        let stores = [
+            InMemoryStore::<Entity>::new(),
+            InMemoryStore::<Entity>::new(),
+        ];
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let loads = std::sync::atomic::AtomicUsize::new(0);
+
+        std::thread::scope(|s| {
+            for store in &stores {
+                for _ in 0..4 {
+                    let mut redis_conn = client.get_connection().unwrap();
+                    let loads = &loads;
+                    s.spawn(move || {
+                        let loader = || {
+                            loads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                            std::thread::sleep(Duration::from_millis(50));
+                            Ok::<_, std::io::Error>(Entity { x: 1.0, y: 0.0 })
+                        };
+                        let val = store.get_or_load(key0, loader, &mut redis_conn).unwrap();
+                        assert_eq!(*val, Entity { x: 1.0, y: 0.0 });
+                    });
+                }
+            }
+        });
+
+        assert_eq!(loads.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(!is_load_locked(&mut ctx.redis_conn, key0));
+
+        let val = ctx
+            .in_memory_store
+            .get_or_load(
+                key0,
+                || Err::<Entity, _>(std::io::Error::other("loaded a cached key")),
+                &mut ctx.redis_conn,
+            )
             .unwrap();
-        redis::cmd("del")
-            .arg("some-key".to_string())
-            .query::<bool>(&mut ctx.redis_conn)
+        assert_eq!(*val, Entity { x: 1.0, y: 0.0 });
+    }
+
+    #[test]
+    fn test_get_or_load_waits_for_lock_holder() {
+        let mut ctx = setup::<Entity>("get_or_load_waits_for_lock_holder");
+        let key0 = &ctx.key("key-0");
+        let other_store = InMemoryStore::<Entity>::new();
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let mut other_conn = client.get_connection().unwrap();
+
+        // another process is loading key-0
+        let _: () = redis::cmd("SET")
+            .arg(format!("ccache:load:{}", key0))
+            .arg("other")
+            .arg("PX")
+            .arg(2000)
+            .query(&mut ctx.redis_conn)
             .unwrap();
 
-        let result = in_memory_store
-            .get("some-key", &mut ctx.redis_conn)
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(100));
+                other_store
+                    .insert_if_match(key0, Entity { x: 2.0, y: 0.0 }, None, &mut other_conn)
+                    .unwrap();
+            });
+
+            let val = ctx
+                .in_memory_store
+                .get_or_load(
+                    key0,
+                    || Err::<Entity, _>(std::io::Error::other("loaded while locked")),
+                    &mut ctx.redis_conn,
+                )
+                .unwrap();
+            assert_eq!(*val, Entity { x: 2.0, y: 0.0 });
+        });
+    }
+
+    #[test]
+    fn test_get_or_load_times_out() {
+        let mut ctx = setup::<Entity>("get_or_load_times_out");
+        let key0 = &ctx.key("key-0");
+        let store = InMemoryStore::<Entity>::new().with_load_lock(Duration::from_millis(100));
+
+        // another process holds the lock for longer than load_lock
+        let _: () = redis::cmd("SET")
+            .arg(format!("ccache:load:{}", key0))
+            .arg("other")
+            .arg("PX")
+            .arg(2000)
+            .query(&mut ctx.redis_conn)
             .unwrap();
 
-        assert_eq!(result, GetResult::None);
+        let started = Instant::now();
+        let result = store.get_or_load(
+            key0,
+            || Err::<Entity, _>(std::io::Error::other("loaded while locked")),
+            &mut ctx.redis_conn,
+        );
+        assert!(matches!(result, Err(Error::Timeout(_))));
+        assert!(started.elapsed() < Duration::from_millis(500));
+        // the waiting was no get
+        assert_eq!(store.stats().none, 1);
     }
 
     #[test]
-    fn test_local_miss() {
-        let mut ctx = setup();
-        let in_memory_store = &mut ctx.in_memory_store;
+    fn test_get_or_load_error() {
+        let mut ctx = setup::<Entity>("get_or_load_error");
+        let key0 = &ctx.key("key-0");
+
+        let result = ctx.in_memory_store.get_or_load(
+            key0,
+            || Err::<Entity, _>(std::io::Error::other("database is down")),
+            &mut ctx.redis_conn,
+        );
+        assert!(matches!(result, Err(Error::Load(_))));
+        // nothing was inserted, and the lock is released for the next try
+        assert!(!is_load_locked(&mut ctx.redis_conn, key0));
+        assert_eq!(
+            ctx.in_memory_store.get(key0, &mut ctx.redis_conn).unwrap(),
+            GetResult::None
+        );
+
+        let val = ctx
+            .in_memory_store
+            .get_or_load(
+                key0,
+                || Ok::<_, std::io::Error>(Entity { x: 1.0, y: 0.0 }),
+                &mut ctx.redis_conn,
+            )
+            .unwrap();
+        assert_eq!(*val, Entity { x: 1.0, y: 0.0 });
+    }
+
+    fn follow<T: Serializable>(flight: Flight<'_, T>) -> Arc<RequestSlot<T>> {
+        match flight {
+            Flight::Follower(slot) => slot,
+            Flight::Leader(_) => panic!("expected to follow"),
+        }
+    }
+
+    #[test]
+    fn test_leader_panic_releases_followers() {
+        let store = InMemoryStore::<Entity>::new();
+        let request_key = RequestKey::Miss("key-0".to_string());
+
+        let leader = store.join_request(&request_key);
+        assert!(matches!(leader, Flight::Leader(_)));
+        let slot = follow(store.join_request(&request_key));
+
+        std::thread::scope(|s| {
+            let handle = s.spawn(move || {
+                let _leader = leader;
+                panic!("leader panicked");
+            });
+            assert!(handle.join().is_err());
+        });
+
+        assert!(matches!(
+            store.wait_for_request(&slot, None, store.wait_timeout),
+            Some(Err(Error::Aborted))
+        ));
+        // the slot is gone, the next get leads
+        assert!(matches!(
+            store.join_request(&request_key),
+            Flight::Leader(_)
+        ));
+    }
+
+    #[test]
+    fn test_aborted_leader_is_taken_over() {
+        let store = InMemoryStore::<Entity>::new();
+        let request_key = RequestKey::Miss("key-0".to_string());
+
+        let leader = store.join_request(&request_key);
+        let slot = follow(store.join_request(&request_key));
+        drop(leader);
+
+        // the follower does the request itself rather than return Error::Aborted
+        let result = store.follow(
+            &TraceId::new(),
+            "key-0",
+            &request_key,
+            slot,
+            None,
+            &mut Unreachable,
+        );
+        assert!(matches!(result, Err(Error::Redis(_))));
+        assert!(matches!(
+            store.join_request(&request_key),
+            Flight::Leader(_)
+        ));
+    }
+
+    #[test]
+    fn test_wait_timeout_takes_over() {
+        let store = InMemoryStore::<Entity>::new().with_wait_timeout(Duration::from_millis(50));
+        let request_key = RequestKey::Miss("key-0".to_string());
+
+        // a leader which never publishes
+        let stuck = store.join_request(&request_key);
+
+        let slot = follow(store.join_request(&request_key));
+        assert!(store
+            .wait_for_request(&slot, None, store.wait_timeout)
+            .is_none());
+
+        let leader = match store.take_over(&TraceId::new(), "key-0", &request_key, &slot) {
+            Flight::Leader(leader) => leader,
+            Flight::Follower(_) => panic!("expected to take over"),
+        };
+        // later gets and the other waiters of the stuck slot follow the new leader
+        let next = follow(store.join_request(&request_key));
+        assert!(!Arc::ptr_eq(&next, &slot));
+        assert!(Arc::ptr_eq(
+            &follow(store.take_over(&TraceId::new(), "key-0", &request_key, &slot)),
+            &next
+        ));
+
+        // the stuck leader finishing late doesn't remove the new slot
+        drop(stuck);
+        assert!(matches!(
+            store.wait_for_request(&slot, None, store.wait_timeout),
+            Some(Err(Error::Aborted))
+        ));
+        assert!(Arc::ptr_eq(
+            &follow(store.join_request(&request_key)),
+            &next
+        ));
+
+        leader.finish(RedisResult::None);
+        assert!(matches!(
+            store.wait_for_request(&next, None, store.wait_timeout),
+            Some(Ok(GetResult::None))
+        ));
+        assert!(matches!(
+            store.join_request(&request_key),
+            Flight::Leader(_)
+        ));
+
+        // get doesn't block forever either, it gives up waiting and does the request itself
+        let _stuck = store.join_request(&request_key);
+        assert!(matches!(
+            store.get("key-0", &mut Unreachable),
+            Err(Error::Redis(_))
+        ));
+    }
+
+    #[test]
+    fn test_etags_increase_within_a_second() {
+        let mut ctx = setup("etags_increase_within_a_second");
+        let key = &ctx.key("some-key");
+        let in_memory_store = &ctx.in_memory_store;
+        let other_store = InMemoryStore::<Entity>::new();
+
+        let mut prev_etag = 0u64;
+        for i in 0..10 {
+            let etag = in_memory_store
+                .insert(
+                    key,
+                    Entity {
+                        x: i as f32,
+                        y: 0.0,
+                    },
+                    &mut ctx.redis_conn,
+                )
+                .unwrap();
+            let etag: u64 = String::from_utf8(etag).unwrap().parse().unwrap();
+            assert!(etag > prev_etag);
+            prev_etag = etag;
+
+            // every write is visible to a reader which cached the previous one
+            let result = other_store.get(key, &mut ctx.redis_conn).unwrap();
+            assert_eq!(
+                result,
+                GetResult::New(Arc::new(Entity {
+                    x: i as f32,
+                    y: 0.0
+                }))
+            );
+        }
+
+        // a deleted and inserted again key doesn't go back
+        in_memory_store.remove(key, &mut ctx.redis_conn).unwrap();
+        let etag = in_memory_store
+            .insert(key, Entity { x: 0.0, y: 0.0 }, &mut ctx.redis_conn)
+            .unwrap();
+        let etag: u64 = String::from_utf8(etag).unwrap().parse().unwrap();
+        assert!(etag > prev_etag);
+    }
+
+    async fn setup_async<T: Serializable>() -> (InMemoryStore<T>, redis::aio::MultiplexedConnection)
+    {
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let redis_conn = client.get_multiplexed_async_std_connection().await.unwrap();
+
+        (InMemoryStore::new(), redis_conn)
+    }
+
+    #[async_std::test]
+    async fn test_get_async() {
+        let mut ctx = setup::<Entity>("get_async");
+        let key = &ctx.key("some-key");
+        let (in_memory_store, mut redis_conn) = setup_async().await;
 
-        // insert one entity and update etag
         in_memory_store
-            .insert("some-key", Entity { x: 0.0, y: 4.0 }, &mut ctx.redis_conn)
+            .insert_async(key, Entity { x: 0.0, y: 4.0 }, &mut redis_conn)
+            .await
             .unwrap();
-        in_memory_store.update_etag("some-key", "abc");
 
         let result = in_memory_store
-            .get("some-key", &mut ctx.redis_conn)
-            .unwrap()
+            .get_async(key, &mut redis_conn)
+            .await
             .unwrap();
-        assert_eq!(result.x, 0.0);
-        assert_eq!(result.y, 4.0);
+        assert_eq!(
+            result,
+            GetResult::Unchanged(Arc::new(Entity { x: 0.0, y: 4.0 }))
+        );
+
+        // written by the blocking api, read by the async one
+        ctx.in_memory_store
+            .insert(key, Entity { x: 1.0, y: 2.0 }, &mut ctx.redis_conn)
+            .unwrap();
+
+        let result = in_memory_store
+            .get_async(key, &mut redis_conn)
+            .await
+            .unwrap();
+        assert_eq!(result, GetResult::New(Arc::new(Entity { x: 1.0, y: 2.0 })));
+    }
+
+    #[async_std::test]
+    async fn test_get_async_concurrent() {
+        let ctx = setup::<Entity>("get_async_concurrent");
+        let key = &ctx.key("some-key");
+        let (in_memory_store, mut redis_conn) = setup_async().await;
+        let in_memory_store = Arc::new(in_memory_store);
+
+        in_memory_store
+            .insert_async(key, Entity { x: 0.0, y: 4.0 }, &mut redis_conn)
+            .await
+            .unwrap();
+        in_memory_store.delete(key);
+
+        let tasks: Vec<_> = (0..16)
+            .map(|_| {
+                let store = in_memory_store.clone();
+                let mut conn = redis_conn.clone();
+                let key = key.clone();
+                async_std::task::spawn(async move {
+                    store.get_async(&key, &mut conn).await.unwrap().unwrap()
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            assert_eq!(*task.await, Entity { x: 0.0, y: 4.0 });
+        }
     }
 }
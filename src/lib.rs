@@ -1,5 +1,11 @@
+mod circuit_breaker;
 pub mod errors;
 pub mod in_memory_store;
+mod local_cache;
+pub mod metrics;
 mod partitioned_hash_map;
+pub mod pooled_store;
 pub mod serializable;
+pub mod stats;
 pub mod trace;
+mod tracking;
@@ -0,0 +1,660 @@
+use crate::partitioned_hash_map::PartitionedHashMap;
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+// origin of DataInner::validated_at and leased_until, an Instant doesn't fit in an atomic
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+fn nanos_since_epoch() -> u64 {
+    EPOCH.elapsed().as_nanos() as u64
+}
+
+// Upper bound of the local map. The budget is split between the shards, the first n % shards
+// of them get one more. A shard always keeps its latest entry, even when it alone is over the shard's
+// budget, so the map can go over a budget below the number of shards or below the weight of a value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capacity {
+    Unbounded,
+    // number of entries
+    Entries(usize),
+    // approximate bytes, an entry weighs its key plus its serialized value
+    Bytes(usize),
+}
+
+pub(crate) struct DataInner<T> {
+    etag: Vec<u8>,
+    // the etag as a number, etags of a key only increase
+    version: u64,
+    val: Arc<T>,
+    // serialized length of val
+    size: usize,
+    // when the Redis copy expires, taken conservatively from the start of the request which set or fetched it
+    expires_at: Option<Instant>,
+    // CLOCK reference bit, set by reads and cleared by the eviction hand
+    referenced: AtomicBool,
+    // when Redis last confirmed this is the current version, in nanoseconds since EPOCH
+    validated_at: AtomicU64,
+    // until when Redis promised not to change this version, see lease_lua in in_memory_store, 0 for no lease
+    leased_until: AtomicU64,
+    // sequence number of the key's tracking slot when it was last validated, 0 if never, see Tracker
+    tracked_seq: AtomicU64,
+}
+
+// The etag as a number, 0 if it isn't one.
+pub(crate) fn version_of(etag: &[u8]) -> u64 {
+    std::str::from_utf8(etag)
+        .ok()
+        .and_then(|etag| etag.parse().ok())
+        .unwrap_or(0)
+}
+
+impl<T> DataInner<T> {
+    pub fn new(etag: Vec<u8>, val: Arc<T>, size: usize, expires_at: Option<Instant>) -> Self {
+        Self {
+            version: version_of(&etag),
+            etag,
+            val,
+            size,
+            expires_at,
+            referenced: AtomicBool::new(false),
+            validated_at: AtomicU64::new(nanos_since_epoch()),
+            leased_until: AtomicU64::new(0),
+            tracked_seq: AtomicU64::new(0),
+        }
+    }
+
+    pub fn val(&self) -> Arc<T> {
+        self.val.clone()
+    }
+
+    pub fn etag(&self) -> &Vec<u8> {
+        &self.etag
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        self.expires_at
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expires_at {
+            Some(deadline) => deadline <= Instant::now(),
+            None => false,
+        }
+    }
+
+    pub fn validate(&self) {
+        self.validated_at
+            .fetch_max(nanos_since_epoch(), Ordering::Relaxed);
+    }
+
+    pub fn validated_within(&self, window: Duration) -> bool {
+        let age = nanos_since_epoch().saturating_sub(self.validated_at.load(Ordering::Relaxed));
+
+        (age as u128) < window.as_nanos()
+    }
+
+    pub fn lease(&self, until: Instant) {
+        let until = until.saturating_duration_since(*EPOCH).as_nanos() as u64;
+        self.leased_until.fetch_max(until, Ordering::Relaxed);
+    }
+
+    pub fn is_leased(&self) -> bool {
+        nanos_since_epoch() < self.leased_until.load(Ordering::Relaxed)
+    }
+
+    pub fn track(&self, seq: u64) {
+        self.tracked_seq.store(seq, Ordering::Relaxed);
+    }
+
+    pub fn tracked_seq(&self) -> u64 {
+        self.tracked_seq.load(Ordering::Relaxed)
+    }
+
+    // avoid writing the cache line when the bit is already set, reads are the hot path
+    pub fn touch(&self) {
+        if !self.referenced.load(Ordering::Relaxed) {
+            self.referenced.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+type Shard<T> = HashMap<String, Arc<DataInner<T>>>;
+
+// CLOCK state of one shard. It's only modified while holding the shard's write lock,
+// the mutex is there for interior mutability and is never contended.
+struct Clock {
+    // keys of the shard, the hand is the front
+    ring: VecDeque<String>,
+    // keys removed from the shard whose ring slot the hand hasn't reached yet
+    removed: HashSet<String>,
+    weight: usize,
+    budget: usize,
+}
+
+impl Clock {
+    // Marks the ring slot of key as removed. Once removed slots are over half the ring, they're dropped
+    // in one scan, so churn of a shard below its budget can't grow the ring, and a scan is paid
+    // by as many removes as it drops.
+    fn remove(&mut self, key: &str) {
+        self.removed.insert(key.to_string());
+
+        if self.removed.len() > self.ring.len() / 2 {
+            let removed = std::mem::take(&mut self.removed);
+            self.ring.retain(|key| !removed.contains(key));
+        }
+    }
+}
+
+// The local map of an InMemoryStore, a PartitionedHashMap with per shard CLOCK eviction.
+// An evicted key just becomes a local miss.
+pub(crate) struct LocalCache<T> {
+    map: PartitionedHashMap<String, Arc<DataInner<T>>, RandomState>,
+    clocks: Box<[Mutex<Clock>]>,
+    capacity: Capacity,
+    // entries held, and their keys plus serialized values in bytes, for stats
+    entries: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl<T> LocalCache<T> {
+    pub fn new(capacity: Capacity) -> Self {
+        let map = PartitionedHashMap::new();
+        let shards = map.shards_len();
+
+        let budget = |idx: usize| match capacity {
+            Capacity::Unbounded => usize::MAX,
+            Capacity::Entries(n) | Capacity::Bytes(n) => n / shards + usize::from(idx < n % shards),
+        };
+
+        let clocks = (0..shards)
+            .map(|idx| {
+                Mutex::new(Clock {
+                    ring: VecDeque::new(),
+                    removed: HashSet::new(),
+                    weight: 0,
+                    budget: budget(idx),
+                })
+            })
+            .collect();
+
+        Self {
+            map,
+            clocks,
+            capacity,
+            entries: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn read_guard(&self, key: &str) -> RwLockReadGuard<'_, Shard<T>> {
+        self.map.read_guard(key)
+    }
+
+    pub fn write_guard(&self, key: &str) -> RwLockWriteGuard<'_, Shard<T>> {
+        self.map.write_guard(key)
+    }
+
+    // Looks up key in its shard (which must be the shard of key) and marks it as recently used.
+    // An expired entry is treated as missing, Redis has dropped it already.
+    pub fn get(&self, shard: &Shard<T>, key: &str) -> Option<Arc<DataInner<T>>> {
+        let entry = shard.get(key)?;
+        if entry.is_expired() {
+            return None;
+        }
+        entry.touch();
+
+        Some(entry.clone())
+    }
+
+    // Inserts into the shard of key (the caller holds its write lock), then evicts until the shard fits its budget,
+    // or only key is left. Evicting the entry just published would make the next get a miss again.
+    // An entry never replaces a newer version of the key, so concurrent publishers can't make the map go backwards.
+    // Returns whether entry was inserted.
+    pub fn insert(&self, shard: &mut Shard<T>, key: &str, entry: Arc<DataInner<T>>) -> bool {
+        if let Some(current) = shard.get(key) {
+            if current.version >= entry.version {
+                return false;
+            }
+        }
+
+        self.added(key, &entry);
+
+        if self.capacity == Capacity::Unbounded {
+            if let Some(old) = shard.insert(key.to_string(), entry) {
+                self.dropped(key, &old);
+            }
+            return true;
+        }
+
+        let mut clock = self.clock(key);
+        clock.weight += self.weight(key, &entry);
+
+        match shard.insert(key.to_string(), entry) {
+            Some(old) => {
+                clock.weight -= self.weight(key, &old);
+                self.dropped(key, &old);
+            }
+            // a key removed and inserted again takes its old slot back
+            None if clock.removed.remove(key) => {}
+            None => clock.ring.push_back(key.to_string()),
+        }
+
+        while clock.weight > clock.budget {
+            let candidate = match clock.ring.pop_front() {
+                Some(candidate) => candidate,
+                None => break,
+            };
+
+            if clock.removed.remove(&candidate) {
+                continue;
+            }
+
+            if candidate == key {
+                clock.ring.push_back(candidate);
+                if clock.ring.len() == 1 {
+                    break;
+                }
+                continue;
+            }
+
+            let entry = &shard[&candidate];
+            if !entry.is_expired() && entry.referenced.swap(false, Ordering::Relaxed) {
+                // second chance
+                clock.ring.push_back(candidate);
+            } else {
+                clock.weight -= self.weight(&candidate, entry);
+                self.dropped(&candidate, entry);
+                shard.remove(&candidate);
+            }
+        }
+
+        true
+    }
+
+    // Inserts under the write lock of key's shard, taken just for this. See insert.
+    pub fn publish(&self, key: &str, entry: Arc<DataInner<T>>) -> bool {
+        let mut shard = self.write_guard(key);
+
+        self.insert(&mut shard, key, entry)
+    }
+
+    // Drops key unless its local copy is newer than version, which a remove racing a later write would lose.
+    // Returns whether an entry was dropped.
+    pub fn remove_through(&self, key: &str, version: u64) -> bool {
+        let mut shard = self.write_guard(key);
+
+        if shard.get(key).is_some_and(|entry| entry.version <= version) {
+            return self.remove(&mut shard, key).is_some();
+        }
+
+        false
+    }
+
+    // Removes from the shard of key, the caller holds its write lock.
+    pub fn remove(&self, shard: &mut Shard<T>, key: &str) -> Option<Arc<DataInner<T>>> {
+        let removed = shard.remove(key)?;
+        self.dropped(key, &removed);
+
+        if self.capacity != Capacity::Unbounded {
+            let mut clock = self.clock(key);
+            clock.weight -= self.weight(key, &removed);
+            // its ring slot is dropped when the hand gets there, or when the ring is compacted
+            clock.remove(key);
+        }
+
+        Some(removed)
+    }
+
+    // Drops key if its local copy has expired, returns whether it did.
+    pub fn remove_expired(&self, key: &str) -> bool {
+        let mut shard = self.write_guard(key);
+
+        if shard.get(key).is_some_and(|entry| entry.is_expired()) {
+            return self.remove(&mut shard, key).is_some();
+        }
+
+        false
+    }
+
+    // Entries held and their bytes, see Stats.
+    pub fn entries(&self) -> (u64, u64) {
+        (
+            self.entries.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    fn added(&self, key: &str, entry: &DataInner<T>) {
+        self.entries.fetch_add(1, Ordering::Relaxed);
+        self.bytes
+            .fetch_add((key.len() + entry.size) as u64, Ordering::Relaxed);
+    }
+
+    fn dropped(&self, key: &str, entry: &DataInner<T>) {
+        self.entries.fetch_sub(1, Ordering::Relaxed);
+        self.bytes
+            .fetch_sub((key.len() + entry.size) as u64, Ordering::Relaxed);
+    }
+
+    fn clock(&self, key: &str) -> std::sync::MutexGuard<'_, Clock> {
+        let idx = self.map.shard_idx(key);
+
+        self.clocks[idx].lock().unwrap()
+    }
+
+    fn weight(&self, key: &str, entry: &DataInner<T>) -> usize {
+        match self.capacity {
+            Capacity::Bytes(_) => key.len() + entry.size,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(cache: &LocalCache<u64>, key: &str, size: usize) {
+        let entry = Arc::new(DataInner::new(b"1".to_vec(), Arc::new(0), size, None));
+        let mut shard = cache.write_guard(key);
+        cache.insert(&mut shard, key, entry);
+    }
+
+    fn insert_version(cache: &LocalCache<u64>, key: &str, etag: &str, val: u64) -> bool {
+        let entry = Arc::new(DataInner::new(etag.into(), Arc::new(val), 1, None));
+        let mut shard = cache.write_guard(key);
+        cache.insert(&mut shard, key, entry)
+    }
+
+    fn contains(cache: &LocalCache<u64>, key: &str) -> bool {
+        let shard = cache.read_guard(key);
+        cache.get(&shard, key).is_some()
+    }
+
+    // n keys other than key in the shard of key
+    fn same_shard(cache: &LocalCache<u64>, key: &str, n: usize) -> Vec<String> {
+        (0..100000)
+            .map(|i| format!("other-{}", i))
+            .filter(|other| cache.map.shard_idx(other) == cache.map.shard_idx(key))
+            .take(n)
+            .collect()
+    }
+
+    fn remove(cache: &LocalCache<u64>, key: &str) -> bool {
+        let mut shard = cache.write_guard(key);
+        cache.remove(&mut shard, key).is_some()
+    }
+
+    fn len(cache: &LocalCache<u64>) -> usize {
+        (0..1000)
+            .filter(|i| contains(cache, &format!("key-{}", i)))
+            .count()
+    }
+
+    #[test]
+    fn test_entries_capacity() {
+        let cache = LocalCache::new(Capacity::Entries(128));
+        for i in 0..1000 {
+            insert(&cache, &format!("key-{}", i), 10);
+        }
+
+        // every shard holds at most one entry
+        assert!(len(&cache) <= 128);
+        assert!(len(&cache) > 0);
+    }
+
+    #[test]
+    fn test_entries_capacity_remainder() {
+        // 200 = 128 + 72, so 72 shards hold two entries and the others one
+        let cache = LocalCache::new(Capacity::Entries(200));
+        for i in 0..5000 {
+            insert(&cache, &format!("key-{}", i), 10);
+        }
+
+        assert_eq!(cache.entries().0, 200);
+
+        // below the number of shards, the shards without a budget keep their latest entry
+        let cache = LocalCache::new(Capacity::Entries(50));
+        for i in 0..5000 {
+            insert(&cache, &format!("key-{}", i), 10);
+        }
+
+        assert_eq!(cache.entries().0, 128);
+        assert!(contains(&cache, "key-4999"));
+    }
+
+    #[test]
+    fn test_bytes_capacity() {
+        let cache = LocalCache::new(Capacity::Bytes(128 * 100));
+        for i in 0..1000 {
+            insert(&cache, &format!("key-{}", i), 50);
+        }
+
+        // each entry weighs more than 50 bytes, so a shard keeps at most one
+        assert!(len(&cache) <= 128);
+    }
+
+    #[test]
+    fn test_value_over_shard_budget_kept() {
+        // 100 bytes per shard
+        let cache = LocalCache::new(Capacity::Bytes(128 * 100));
+        let others = same_shard(&cache, "key-0", 2);
+
+        insert(&cache, &others[0], 10);
+        insert(&cache, "key-0", 1000);
+        assert!(contains(&cache, "key-0"));
+        assert!(!contains(&cache, &others[0]));
+        assert_eq!(cache.clock("key-0").ring.len(), 1);
+
+        // the next insert of the shard evicts it
+        insert(&cache, &others[1], 10);
+        assert!(!contains(&cache, "key-0"));
+        assert!(contains(&cache, &others[1]));
+        assert_eq!(cache.entries().0, 1);
+    }
+
+    #[test]
+    fn test_referenced_entry_survives() {
+        // two entries per shard, so keys of the same shard compete
+        let cache = LocalCache::new(Capacity::Entries(128 * 2));
+        let shard_of = |key: &str| cache.map.shard_idx(key);
+
+        let hot = "key-0";
+        let others: Vec<String> = (1..10000)
+            .map(|i| format!("key-{}", i))
+            .filter(|k| shard_of(k) == shard_of(hot))
+            .take(3)
+            .collect();
+
+        insert(&cache, hot, 1);
+        insert(&cache, &others[0], 1);
+        assert!(contains(&cache, hot));
+
+        // hot is referenced, so the hand passes it and evicts others[0]
+        insert(&cache, &others[1], 1);
+        assert!(contains(&cache, hot));
+        assert!(!contains(&cache, &others[0]));
+    }
+
+    #[test]
+    fn test_entries() {
+        let cache = LocalCache::new(Capacity::Bytes(128 * 1024));
+        insert(&cache, "a", 10);
+        insert(&cache, "b", 20);
+        assert_eq!(cache.entries(), (2, 32));
+
+        // replacing a key swaps its bytes
+        insert_version(&cache, "a", "2", 0);
+        assert_eq!(cache.entries(), (2, 23));
+
+        let mut shard = cache.write_guard("b");
+        cache.remove(&mut shard, "b");
+        drop(shard);
+        assert_eq!(cache.entries(), (1, 2));
+
+        // evictions are dropped too
+        let cache = LocalCache::new(Capacity::Entries(128));
+        for i in 0..1000 {
+            insert(&cache, &format!("key-{}", i), 10);
+        }
+        assert_eq!(cache.entries().0 as usize, len(&cache));
+    }
+
+    #[test]
+    fn test_remove() {
+        let cache = LocalCache::new(Capacity::Entries(128 * 3));
+        insert(&cache, "key-0", 1);
+        for key in same_shard(&cache, "key-0", 2) {
+            insert(&cache, &key, 1);
+        }
+
+        assert!(remove(&cache, "key-0"));
+        assert!(!remove(&cache, "key-0"));
+
+        assert_eq!(cache.clock("key-0").weight, 2);
+        // the ring slot stays until the hand reaches it
+        assert_eq!(cache.clock("key-0").ring.len(), 3);
+
+        // inserted again, the key takes its old slot back
+        insert(&cache, "key-0", 1);
+        assert_eq!(cache.clock("key-0").ring.len(), 3);
+        assert!(cache.clock("key-0").removed.is_empty());
+    }
+
+    #[test]
+    fn test_removed_slot_dropped_by_hand() {
+        let cache = LocalCache::new(Capacity::Entries(128 * 3));
+        let others = same_shard(&cache, "key-0", 4);
+        insert(&cache, "key-0", 1);
+        insert(&cache, &others[0], 1);
+        insert(&cache, &others[1], 1);
+        remove(&cache, "key-0");
+
+        // fill the shard of key-0 past its budget of three, the hand passes the removed slot
+        insert(&cache, &others[2], 1);
+        insert(&cache, &others[3], 1);
+
+        let clock = cache.clock("key-0");
+        assert!(clock.removed.is_empty());
+        assert_eq!(clock.ring.len(), 3);
+        assert_eq!(clock.weight, 3);
+    }
+
+    #[test]
+    fn test_removed_slots_compacted() {
+        let cache = LocalCache::new(Capacity::Entries(1 << 20));
+
+        // churn below the budget, the hand never runs
+        for i in 0..200000 {
+            let key = format!("key-{}", i);
+            insert(&cache, &key, 1);
+            remove(&cache, &key);
+        }
+
+        for clock in cache.clocks.iter() {
+            let clock = clock.lock().unwrap();
+            assert!(clock.ring.len() <= 1);
+            assert!(clock.removed.len() <= 1);
+        }
+
+        // live keys keep their slots
+        let others = same_shard(&cache, "key-0", 5);
+        for key in &others {
+            insert(&cache, key, 1);
+        }
+        remove(&cache, &others[0]);
+        remove(&cache, &others[1]);
+        assert_eq!(cache.clock("key-0").ring.len(), 5);
+        remove(&cache, &others[2]);
+        assert_eq!(cache.clock("key-0").ring.len(), 2);
+        assert!(cache.clock("key-0").removed.is_empty());
+    }
+
+    #[test]
+    fn test_remove_through() {
+        let cache = LocalCache::new(Capacity::Entries(128));
+        assert!(insert_version(&cache, "key-0", "1700000000000002", 2));
+
+        assert!(!cache.remove_through("key-0", 1700000000000001));
+        assert!(contains(&cache, "key-0"));
+        assert!(cache.remove_through("key-0", 1700000000000002));
+        assert!(!contains(&cache, "key-0"));
+        assert!(!cache.remove_through("key-0", 1700000000000002));
+    }
+
+    #[test]
+    fn test_expired_entry() {
+        let cache = LocalCache::new(Capacity::Unbounded);
+        let deadline = Instant::now() + Duration::from_millis(20);
+        let entry = Arc::new(DataInner::new(
+            b"1".to_vec(),
+            Arc::new(0),
+            1,
+            Some(deadline),
+        ));
+        cache.insert(&mut cache.write_guard("key-0"), "key-0", entry);
+
+        assert!(contains(&cache, "key-0"));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!contains(&cache, "key-0"));
+
+        cache.remove_expired("key-0");
+        assert!(cache.read_guard("key-0").is_empty());
+    }
+
+    #[test]
+    fn test_validated_within() {
+        let entry = DataInner::new(b"1".to_vec(), Arc::new(0), 1, None);
+        assert!(entry.validated_within(Duration::from_millis(20)));
+        assert!(!entry.validated_within(Duration::ZERO));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!entry.validated_within(Duration::from_millis(20)));
+
+        entry.validate();
+        assert!(entry.validated_within(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn test_lease() {
+        let entry = DataInner::new(b"1".to_vec(), Arc::new(0), 1, None);
+        assert!(!entry.is_leased());
+
+        entry.lease(Instant::now() + Duration::from_millis(20));
+        // a shorter lease doesn't cut the current one
+        entry.lease(Instant::now());
+        assert!(entry.is_leased());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!entry.is_leased());
+    }
+
+    #[test]
+    fn test_older_version_does_not_replace() {
+        let cache = LocalCache::new(Capacity::Entries(1024));
+
+        assert!(insert_version(&cache, "key-0", "1700000000000002", 2));
+        assert!(!insert_version(&cache, "key-0", "1700000000000001", 1));
+        assert!(!insert_version(&cache, "key-0", "1700000000000002", 2));
+        assert!(insert_version(&cache, "key-0", "1700000000000003", 3));
+
+        let shard = cache.read_guard("key-0");
+        assert_eq!(*cache.get(&shard, "key-0").unwrap().val(), 3);
+    }
+}
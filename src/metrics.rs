@@ -0,0 +1,354 @@
+use crate::stats::{Histogram, Stats, LATENCY_BUCKETS};
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// a scrape which doesn't send its request line or read the response in time is dropped,
+// so it can't stall the listener
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+type StatsFn = Box<dyn Fn() -> Stats + Send + Sync>;
+type Field = fn(&Stats) -> u64;
+
+// Named stores to render, e.g.
+//   let registry = Arc::new(Registry::new());
+//   let store = Arc::new(InMemoryStore::<World>::new());
+//   registry.register("worlds", { let store = store.clone(); move || store.stats() });
+//   registry.serve("0.0.0.0:9091")?;
+#[derive(Default)]
+pub struct Registry {
+    stores: Mutex<Vec<(String, StatsFn)>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Adds a store under name, stats is called on each render.
+    pub fn register<F>(&self, name: &str, stats: F)
+    where
+        F: Fn() -> Stats + Send + Sync + 'static,
+    {
+        self.stores
+            .lock()
+            .unwrap()
+            .push((name.to_string(), Box::new(stats)));
+    }
+
+    pub fn render(&self) -> String {
+        let stores = self.stores.lock().unwrap();
+        let snapshots: Vec<(&str, Stats)> = stores
+            .iter()
+            .map(|(name, stats)| (name.as_str(), stats()))
+            .collect();
+
+        render(&snapshots)
+    }
+
+    // Serves the rendered stores on GET /metrics from a background thread, returns the bound address.
+    // Connections are handled one at a time, scrapes are rare and cheap.
+    pub fn serve<A: ToSocketAddrs>(self: &Arc<Self>, addr: A) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let registry = self.clone();
+
+        thread::Builder::new()
+            .name("ccache-metrics".to_string())
+            .spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    // a broken scrape only affects itself
+                    let _ = registry.respond(stream);
+                }
+            })?;
+
+        Ok(local_addr)
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+
+        // only the request line matters, the rest of the request is ignored
+        let mut request = [0; 1024];
+        let mut len = 0;
+        while len < request.len() && !request[..len].contains(&b'\n') {
+            match stream.read(&mut request[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+
+        let mut parts = request[..len].split(|b| *b == b' ');
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some(b"GET"), Some(b"/metrics")) => ("200 OK", self.render()),
+            _ => ("404 Not Found", String::new()),
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+}
+
+// Renders the stats of named stores in the Prometheus text exposition format, labelled by store.
+pub fn render(stores: &[(&str, Stats)]) -> String {
+    let mut out = String::new();
+
+    let results: [(&str, Field); 6] = [
+        ("unchanged", |s| s.unchanged),
+        ("unvalidated", |s| s.unvalidated),
+        ("stale", |s| s.stale),
+        ("new", |s| s.new),
+        ("none", |s| s.none),
+        ("error", |s| s.errors),
+    ];
+    header(&mut out, "ccache_gets_total", "counter", "Gets by result.");
+    for (name, stats) in stores {
+        for (result, value) in results {
+            let _ = writeln!(
+                out,
+                "ccache_gets_total{{store=\"{}\",result=\"{}\"}} {}",
+                escape(name),
+                result,
+                value(stats)
+            );
+        }
+    }
+
+    write_metric(
+        &mut out,
+        stores,
+        "ccache_coalesced_gets_total",
+        "counter",
+        "Gets which waited for the request of another get.",
+        |s| s.coalesced,
+    );
+    write_metric(
+        &mut out,
+        stores,
+        "ccache_fetched_bytes_total",
+        "counter",
+        "Payload bytes of the values fetched from Redis.",
+        |s| s.bytes_fetched,
+    );
+    write_metric(
+        &mut out,
+        stores,
+        "ccache_removed_remote_total",
+        "counter",
+        "Local copies dropped as Redis no longer had their key.",
+        |s| s.removed_remote,
+    );
+    write_metric(
+        &mut out,
+        stores,
+        "ccache_entries",
+        "gauge",
+        "Local copies held.",
+        |s| s.entries,
+    );
+    write_metric(
+        &mut out,
+        stores,
+        "ccache_entry_bytes",
+        "gauge",
+        "Keys plus serialized values of the local copies held, in bytes.",
+        |s| s.entry_bytes,
+    );
+
+    header(
+        &mut out,
+        "ccache_redis_request_duration_seconds",
+        "histogram",
+        "Latency of validation requests to Redis, a get_many batch is one request.",
+    );
+    for (name, stats) in stores {
+        write_histogram(
+            &mut out,
+            "ccache_redis_request_duration_seconds",
+            &escape(name),
+            &stats.redis_latency,
+        );
+    }
+    header(
+        &mut out,
+        "ccache_decode_duration_seconds",
+        "histogram",
+        "Latency of decoding values fetched from Redis.",
+    );
+    for (name, stats) in stores {
+        write_histogram(
+            &mut out,
+            "ccache_decode_duration_seconds",
+            &escape(name),
+            &stats.decode_latency,
+        );
+    }
+
+    out
+}
+
+fn write_metric(
+    out: &mut String,
+    stores: &[(&str, Stats)],
+    metric: &str,
+    kind: &str,
+    help: &str,
+    value: Field,
+) {
+    header(out, metric, kind, help);
+    for (name, stats) in stores {
+        let _ = writeln!(
+            out,
+            "{}{{store=\"{}\"}} {}",
+            metric,
+            escape(name),
+            value(stats)
+        );
+    }
+}
+
+fn header(out: &mut String, metric: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", metric, help);
+    let _ = writeln!(out, "# TYPE {} {}", metric, kind);
+}
+
+fn write_histogram(out: &mut String, metric: &str, store: &str, histogram: &Histogram) {
+    let mut cumulative = 0;
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+        cumulative += count;
+        let _ = writeln!(
+            out,
+            "{}_bucket{{store=\"{}\",le=\"{}\"}} {}",
+            metric,
+            store,
+            bound.as_secs_f64(),
+            cumulative
+        );
+    }
+    // the buckets and count are read one after another while observations go on, so the total is
+    // taken from the buckets, otherwise +Inf could fall below the last bucket
+    let total = cumulative + histogram.buckets[LATENCY_BUCKETS.len()];
+    let _ = writeln!(
+        out,
+        "{}_bucket{{store=\"{}\",le=\"+Inf\"}} {}",
+        metric, store, total
+    );
+    let _ = writeln!(
+        out,
+        "{}_sum{{store=\"{}\"}} {}",
+        metric,
+        store,
+        Duration::from_nanos(histogram.sum_nanos).as_secs_f64()
+    );
+    let _ = writeln!(out, "{}_count{{store=\"{}\"}} {}", metric, store, total);
+}
+
+// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats() -> Stats {
+        let mut stats = Stats {
+            unchanged: 3,
+            new: 2,
+            errors: 1,
+            entries: 5,
+            ..Stats::default()
+        };
+        stats.redis_latency.buckets[0] = 2;
+        stats.redis_latency.buckets[4] = 1;
+        stats.redis_latency.buckets[LATENCY_BUCKETS.len()] = 1;
+        stats.redis_latency.count = 4;
+        stats.redis_latency.sum_nanos = 1_500_000_000;
+
+        stats
+    }
+
+    #[test]
+    fn test_render() {
+        let out = render(&[("worlds", stats()), ("a\"b", Stats::default())]);
+
+        assert!(out.contains("# TYPE ccache_gets_total counter\n"));
+        assert!(out.contains("ccache_gets_total{store=\"worlds\",result=\"unchanged\"} 3\n"));
+        assert!(out.contains("ccache_gets_total{store=\"worlds\",result=\"error\"} 1\n"));
+        assert!(out.contains("ccache_entries{store=\"worlds\"} 5\n"));
+        assert!(out.contains("ccache_entries{store=\"a\\\"b\"} 0\n"));
+
+        // buckets are cumulative
+        let metric = "ccache_redis_request_duration_seconds";
+        assert!(out.contains(&format!(
+            "{}_bucket{{store=\"worlds\",le=\"0.00005\"}} 2\n",
+            metric
+        )));
+        assert!(out.contains(&format!(
+            "{}_bucket{{store=\"worlds\",le=\"0.001\"}} 3\n",
+            metric
+        )));
+        assert!(out.contains(&format!(
+            "{}_bucket{{store=\"worlds\",le=\"1\"}} 3\n",
+            metric
+        )));
+        assert!(out.contains(&format!(
+            "{}_bucket{{store=\"worlds\",le=\"+Inf\"}} 4\n",
+            metric
+        )));
+        assert!(out.contains(&format!("{}_sum{{store=\"worlds\"}} 1.5\n", metric)));
+        assert!(out.contains(&format!("{}_count{{store=\"worlds\"}} 4\n", metric)));
+    }
+
+    #[test]
+    fn test_histogram_total_from_buckets() {
+        // an observation landed in a bucket after count was read
+        let mut stats = stats();
+        stats.redis_latency.buckets[0] += 1;
+
+        let out = render(&[("worlds", stats)]);
+        let metric = "ccache_redis_request_duration_seconds";
+        assert!(out.contains(&format!(
+            "{}_bucket{{store=\"worlds\",le=\"+Inf\"}} 5\n",
+            metric
+        )));
+        assert!(out.contains(&format!("{}_count{{store=\"worlds\"}} 5\n", metric)));
+    }
+
+    #[test]
+    fn test_serve() {
+        let registry = Arc::new(Registry::new());
+        registry.register("worlds", stats);
+        let addr = registry.serve("127.0.0.1:0").unwrap();
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&registry.render()));
+
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
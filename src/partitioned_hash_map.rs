@@ -1,7 +1,8 @@
+use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use core::hash::{BuildHasher, Hash, Hasher};
+use core::hash::{BuildHasher, Hash};
 use std::collections::hash_map::RandomState;
 
 pub struct PartitionedHashMap<K, V, S> {
@@ -9,7 +10,7 @@ pub struct PartitionedHashMap<K, V, S> {
     hasher: S,
 }
 
-impl<'a, K: 'a + Eq + Hash + core::fmt::Debug, V: Clone> PartitionedHashMap<K, V, RandomState> {
+impl<'a, K: 'a + Eq + Hash, V> PartitionedHashMap<K, V, RandomState> {
     pub fn new() -> Self {
         let hasher = RandomState::default();
 
@@ -20,30 +21,37 @@ impl<'a, K: 'a + Eq + Hash + core::fmt::Debug, V: Clone> PartitionedHashMap<K, V
         Self { shards, hasher }
     }
 
-    pub fn get_through_shard(
-        &self,
-        key: &K,
-        read_shard: &RwLockReadGuard<'a, HashMap<K, V>>,
-    ) -> Option<V> {
-        read_shard.get(key).cloned()
-    }
-
-    pub fn write_guard(&'a self, key: &K) -> RwLockWriteGuard<'a, HashMap<K, V>> {
+    // Shards are picked by a borrowed form of the key too, e.g. a &str for a String key, so lookups don't allocate.
+    pub fn write_guard<Q>(&'a self, key: &Q) -> RwLockWriteGuard<'a, HashMap<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized,
+    {
         let idx = self.shard_idx(key);
 
         unsafe { self._write_shard(idx) }
     }
 
-    pub fn read_guard(&'a self, key: &K) -> RwLockReadGuard<'a, HashMap<K, V>> {
-        let idx = self.shard_idx(&key);
+    pub fn read_guard<Q>(&'a self, key: &Q) -> RwLockReadGuard<'a, HashMap<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized,
+    {
+        let idx = self.shard_idx(key);
 
         unsafe { self._read_shard(idx) }
     }
 
-    fn shard_idx(&self, key: &K) -> usize {
-        let mut hasher = self.hasher.build_hasher();
-        key.hash(&mut hasher);
-        let hash = hasher.finish() as usize;
+    pub fn shards_len(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn shard_idx<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key) as usize;
         hash % 128
     }
 
@@ -74,4 +82,13 @@ mod tests {
         let rv = read_shard.get(&1).unwrap();
         assert_eq!(rv, &2);
     }
+
+    #[test]
+    fn test_borrowed_key() {
+        let map = PartitionedHashMap::new();
+        map.write_guard("key-0").insert("key-0".to_string(), 1);
+
+        assert_eq!(map.shard_idx("key-0"), map.shard_idx(&"key-0".to_string()));
+        assert_eq!(map.read_guard("key-0").get("key-0"), Some(&1));
+    }
 }
@@ -0,0 +1,173 @@
+use crate::errors::Error;
+use crate::in_memory_store::{GetResult, InMemoryStore, ReadOptions, Versioned};
+use crate::serializable::Serializable;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+// An InMemoryStore which owns a pool of blocking Redis connections,
+// so callers don't need to bring (and serialize on) their own connection.
+pub struct PooledStore<T: Serializable> {
+    store: InMemoryStore<T>,
+    pool: r2d2::Pool<redis::Client>,
+}
+
+impl<T: Serializable> PooledStore<T> {
+    pub fn new(redis_url: &str, max_size: u32) -> Result<Self, redis::RedisError> {
+        let client = redis::Client::open(redis_url)?;
+        let pool = r2d2::Pool::builder()
+            .max_size(max_size)
+            .build(client)
+            .map_err(pool_error)?;
+
+        Ok(Self::with_pool(InMemoryStore::new(), pool))
+    }
+
+    pub fn with_pool(store: InMemoryStore<T>, pool: r2d2::Pool<redis::Client>) -> Self {
+        Self { store, pool }
+    }
+
+    pub fn store(&self) -> &InMemoryStore<T> {
+        &self.store
+    }
+
+    pub fn pool(&self) -> &r2d2::Pool<redis::Client> {
+        &self.pool
+    }
+
+    pub fn insert(&self, key: &str, val: T) -> Result<Vec<u8>, Error> {
+        let mut conn = self.connection()?;
+
+        self.store.insert(key, val, &mut *conn)
+    }
+
+    pub fn insert_with_ttl(&self, key: &str, val: T, ttl: Duration) -> Result<Vec<u8>, Error> {
+        let mut conn = self.connection()?;
+
+        self.store.insert_with_ttl(key, val, ttl, &mut *conn)
+    }
+
+    pub fn insert_many(&self, entries: Vec<(&str, T)>) -> Result<Vec<Vec<u8>>, Error> {
+        let mut conn = self.connection()?;
+
+        self.store.insert_many(entries, &mut *conn)
+    }
+
+    pub fn insert_if_match(
+        &self,
+        key: &str,
+        val: T,
+        expected_etag: Option<&[u8]>,
+    ) -> Result<Vec<u8>, Error> {
+        let mut conn = self.connection()?;
+
+        self.store
+            .insert_if_match(key, val, expected_etag, &mut *conn)
+    }
+
+    pub fn update<F: FnMut(Option<&T>) -> T>(&self, key: &str, f: F) -> Result<Vec<u8>, Error> {
+        let mut conn = self.connection()?;
+
+        self.store.update(key, f, &mut *conn)
+    }
+
+    pub fn get_or_load<F, E>(&self, key: &str, loader: F) -> Result<Arc<T>, Error>
+    where
+        F: FnOnce() -> Result<T, E>,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let mut conn = self.connection()?;
+
+        self.store.get_or_load(key, loader, &mut *conn)
+    }
+
+    pub fn get(&self, key: &str) -> Result<GetResult<Arc<T>>, Error> {
+        let mut conn = self.connection()?;
+
+        self.store.get(key, &mut *conn)
+    }
+
+    pub fn get_with_options(
+        &self,
+        key: &str,
+        options: &ReadOptions,
+    ) -> Result<GetResult<Arc<T>>, Error> {
+        let mut conn = self.connection()?;
+
+        self.store.get_with_options(key, options, &mut *conn)
+    }
+
+    pub fn get_with_etag(&self, key: &str) -> Result<GetResult<Versioned<T>>, Error> {
+        let mut conn = self.connection()?;
+
+        self.store.get_with_etag(key, &mut *conn)
+    }
+
+    pub fn get_many(&self, keys: &[&str]) -> Result<Vec<GetResult<Arc<T>>>, Error> {
+        let mut conn = self.connection()?;
+
+        self.store.get_many(keys, &mut *conn)
+    }
+
+    pub fn remove(&self, key: &str) -> Result<bool, Error> {
+        let mut conn = self.connection()?;
+
+        self.store.remove(key, &mut *conn)
+    }
+
+    fn connection(&self) -> Result<r2d2::PooledConnection<redis::Client>, redis::RedisError> {
+        self.pool.get().map_err(pool_error)
+    }
+}
+
+fn pool_error(e: r2d2::Error) -> redis::RedisError {
+    redis::RedisError::from((
+        redis::ErrorKind::IoError,
+        "redis connection pool error",
+        e.to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate flate2;
+    use super::*;
+    use crate::errors::DecodeError;
+    use crate::errors::EncodeError;
+    use bincode::{Decode, Encode};
+    use derive::Serializable;
+    use flate2::Compression;
+    use std::io::Write;
+    use std::thread;
+
+    #[derive(Encode, Decode, Serializable, PartialEq, Debug, Clone)]
+    struct Entity {
+        x: f32,
+        y: f32,
+    }
+
+    #[test]
+    fn test_pooled_get_across_threads() {
+        let store: Arc<PooledStore<Entity>> =
+            Arc::new(PooledStore::new("redis://127.0.0.1/", 4).unwrap());
+        store
+            .insert("pooled-key", Entity { x: 0.0, y: 4.0 })
+            .unwrap();
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || store.get("pooled-key").unwrap().unwrap())
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(*handle.join().unwrap(), Entity { x: 0.0, y: 4.0 });
+        }
+
+        let _: () = redis::cmd("DEL")
+            .arg("pooled-key")
+            .query(&mut *store.pool().get().unwrap())
+            .unwrap();
+    }
+}
@@ -1,8 +1,8 @@
-use std::fmt::Debug;
+use std::error::Error;
 
 pub trait Serializable {
-    type EncodeError: Debug;
-    type DecodeError: Debug;
+    type EncodeError: Error + Send + Sync + 'static;
+    type DecodeError: Error + Send + Sync + 'static;
     type Config;
 
     fn config() -> Self::Config;
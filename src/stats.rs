@@ -0,0 +1,217 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::errors::Error;
+use crate::in_memory_store::GetResult;
+
+// Upper bounds of the latency histogram buckets, the last bucket has none.
+pub const LATENCY_BUCKETS: [Duration; 14] = [
+    Duration::from_micros(50),
+    Duration::from_micros(100),
+    Duration::from_micros(250),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_micros(2500),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+];
+
+const BUCKETS: usize = LATENCY_BUCKETS.len() + 1;
+
+// Stripes of Counters, a thread always counts into the same one.
+const STRIPES: usize = 16;
+
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // threads take the stripes in turn
+    static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed) % STRIPES;
+}
+
+// A snapshot of the counters of an InMemoryStore, see InMemoryStore::stats.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    // results of gets, by kind
+    pub unchanged: u64,
+    pub unvalidated: u64,
+    pub stale: u64,
+    pub new: u64,
+    pub none: u64,
+    pub errors: u64,
+    // gets which waited for the request of another get instead of asking Redis themselves
+    pub coalesced: u64,
+    // payload bytes of the values fetched from Redis
+    pub bytes_fetched: u64,
+    // local copies dropped as Redis no longer had their key, it was deleted, expired or evicted
+    pub removed_remote: u64,
+    // local copies currently held, and their keys plus serialized values in bytes
+    pub entries: u64,
+    pub entry_bytes: u64,
+    // latencies of validation requests to Redis (one per get_many batch), and of decoding values
+    pub redis_latency: Histogram,
+    pub decode_latency: Histogram,
+}
+
+// A snapshot of a latency histogram.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+    // observations per bucket of LATENCY_BUCKETS, not cumulative, the last one is over the largest bound
+    pub buckets: [u64; BUCKETS],
+    pub count: u64,
+    pub sum_nanos: u64,
+}
+
+impl Histogram {
+    fn merge(&mut self, other: &Histogram) {
+        for (bucket, n) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += n;
+        }
+        self.count += other.count;
+        self.sum_nanos += other.sum_nanos;
+    }
+}
+
+// A live histogram, see Histogram.
+#[derive(Default)]
+pub(crate) struct Latencies {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Latencies {
+    pub fn observe(&self, took: Duration) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| took <= *bound)
+            .unwrap_or(BUCKETS - 1);
+
+        incr(&self.buckets[bucket]);
+        incr(&self.count);
+        add(&self.sum_nanos, took.as_nanos() as u64);
+    }
+
+    pub fn snapshot(&self) -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+            count: self.count.load(Ordering::Relaxed),
+            sum_nanos: self.sum_nanos.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// The live counters of the threads of one stripe, see Counters. Aligned to a cache line,
+// so threads counting into different stripes don't write the same line.
+#[derive(Default)]
+#[repr(align(64))]
+pub(crate) struct Stripe {
+    pub unchanged: AtomicU64,
+    pub unvalidated: AtomicU64,
+    pub stale: AtomicU64,
+    pub new: AtomicU64,
+    pub none: AtomicU64,
+    pub errors: AtomicU64,
+    pub coalesced: AtomicU64,
+    pub bytes_fetched: AtomicU64,
+    pub removed_remote: AtomicU64,
+    pub redis_latency: Latencies,
+    pub decode_latency: Latencies,
+}
+
+// The live counters, striped by thread so gets of different threads don't contend on them,
+// and summed by snapshot. They're relaxed atomics, a snapshot isn't consistent across counters.
+#[derive(Default)]
+pub(crate) struct Counters {
+    stripes: [Stripe; STRIPES],
+}
+
+impl Counters {
+    // The stripe of the current thread.
+    pub fn stripe(&self) -> &Stripe {
+        &self.stripes[STRIPE.with(|stripe| *stripe)]
+    }
+
+    // Counts result under its kind.
+    pub fn result<V>(&self, result: &Result<GetResult<V>, Error>) {
+        let stripe = self.stripe();
+        let counter = match result {
+            Ok(GetResult::Unchanged(_)) => &stripe.unchanged,
+            Ok(GetResult::Unvalidated(_)) => &stripe.unvalidated,
+            Ok(GetResult::Stale(..)) => &stripe.stale,
+            Ok(GetResult::New(_)) => &stripe.new,
+            Ok(GetResult::None) => &stripe.none,
+            Err(_) => &stripe.errors,
+        };
+
+        incr(counter);
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut stats = Stats::default();
+
+        for stripe in &self.stripes {
+            stats.unchanged += load(&stripe.unchanged);
+            stats.unvalidated += load(&stripe.unvalidated);
+            stats.stale += load(&stripe.stale);
+            stats.new += load(&stripe.new);
+            stats.none += load(&stripe.none);
+            stats.errors += load(&stripe.errors);
+            stats.coalesced += load(&stripe.coalesced);
+            stats.bytes_fetched += load(&stripe.bytes_fetched);
+            stats.removed_remote += load(&stripe.removed_remote);
+            stats.redis_latency.merge(&stripe.redis_latency.snapshot());
+            stats
+                .decode_latency
+                .merge(&stripe.decode_latency.snapshot());
+        }
+
+        stats
+    }
+}
+
+pub(crate) fn incr(counter: &AtomicU64) {
+    add(counter, 1);
+}
+
+pub(crate) fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_sums_stripes() {
+        let counters = Counters::default();
+
+        // more threads than stripes, so some share one
+        std::thread::scope(|s| {
+            for _ in 0..STRIPES * 2 {
+                s.spawn(|| {
+                    counters.result::<()>(&Ok(GetResult::None));
+                    counters
+                        .stripe()
+                        .redis_latency
+                        .observe(Duration::from_millis(2));
+                });
+            }
+        });
+
+        let stats = counters.snapshot();
+        assert_eq!(stats.none, STRIPES as u64 * 2);
+        assert_eq!(stats.redis_latency.count, STRIPES as u64 * 2);
+        assert_eq!(stats.redis_latency.buckets[5], STRIPES as u64 * 2);
+        assert_eq!(
+            stats.redis_latency.sum_nanos,
+            STRIPES as u64 * 2 * 2_000_000
+        );
+    }
+}
@@ -1,23 +1,124 @@
 use std::os::raw::c_char;
+use std::time::{Duration, Instant};
 
+use once_cell::sync::{Lazy, OnceCell};
+use uuid::Uuid;
+
+use crate::errors::Error;
+use crate::in_memory_store::GetResult;
+
+// Layout version of Event, bumped on any change of it. Decoders check it before reading the rest,
+// see examples/bpftrace.
+pub const EVENT_VERSION: u16 = 2;
+
+// Bytes of the key an Event carries, Event::key_len holds the full length
+pub const KEY_CAPACITY: usize = 128;
+
+// origin of Event::monotonic_ns
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+// What a get returned, see Event::result.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventResult {
+    // the event isn't the outcome of a get
+    Unknown = 0,
+    Unchanged = 1,
+    Unvalidated = 2,
+    Stale = 3,
+    New = 4,
+    None = 5,
+    Error = 6,
+}
+
+impl EventResult {
+    pub fn of<V>(result: &Result<GetResult<V>, Error>) -> Self {
+        match result {
+            Ok(GetResult::Unchanged(_)) => EventResult::Unchanged,
+            Ok(GetResult::Unvalidated(_)) => EventResult::Unvalidated,
+            Ok(GetResult::Stale(..)) => EventResult::Stale,
+            Ok(GetResult::New(_)) => EventResult::New,
+            Ok(GetResult::None) => EventResult::None,
+            Err(_) => EventResult::Error,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventResult::Unknown => "unknown",
+            EventResult::Unchanged => "unchanged",
+            EventResult::Unvalidated => "unvalidated",
+            EventResult::Stale => "stale",
+            EventResult::New => "new",
+            EventResult::None => "none",
+            EventResult::Error => "error",
+        }
+    }
+}
+
+// The argument of the ccache:store and ccache:breaker probes. Fields are ordered so there's no padding,
+// a decoder can mirror the struct as is.
 #[repr(C)]
 pub struct Event {
+    pub version: u16,
+    // an EventResult
+    pub result: u8,
+    pub reserved: u8,
+    // length of the whole key, which may be longer than key holds
+    pub key_len: u32,
+    // nanoseconds since the first event of the process
+    pub monotonic_ns: u64,
+    // nanoseconds since the first traced event of the operation, zero without a trace id
+    pub duration_ns: u64,
+    // payload bytes the event is about, e.g. a value fetched or written
+    pub bytes: u64,
+    // FNV-1a of the whole key
+    pub key_hash: u64,
+    // a binary UUID, all zero without a trace id
+    pub trace_id: [u8; 16],
     pub method: [c_char; 32],
     pub event: [c_char; 16],
-    pub key: [c_char; 32],
-    pub trace_id: [c_char; 32],
+    pub key: [u8; KEY_CAPACITY],
 }
 
 impl Event {
-    pub fn new(method: &str, event: &str, key: &str, trace_id: &str) -> Self {
+    pub fn new(method: &str, event: &str, key: &str) -> Self {
+        let mut fixed_key = [0; KEY_CAPACITY];
+        let len = key.len().min(KEY_CAPACITY);
+        fixed_key[..len].copy_from_slice(&key.as_bytes()[..len]);
+
         Self {
+            version: EVENT_VERSION,
+            result: EventResult::Unknown as u8,
+            reserved: 0,
+            key_len: key.len().min(u32::MAX as usize) as u32,
+            monotonic_ns: EPOCH.elapsed().as_nanos() as u64,
+            duration_ns: 0,
+            bytes: 0,
+            key_hash: fnv1a(key.as_bytes()),
+            trace_id: [0; 16],
             method: Self::str_to_fixed(method),
             event: Self::str_to_fixed(event),
-            key: Self::str_to_fixed(key),
-            trace_id: Self::str_to_fixed(trace_id),
+            key: fixed_key,
         }
     }
 
+    pub fn with_trace_id(mut self, trace_id: &TraceId) -> Self {
+        self.trace_id = *trace_id.as_bytes();
+        self.duration_ns = trace_id.elapsed().as_nanos() as u64;
+        self
+    }
+
+    pub fn with_result(mut self, result: EventResult) -> Self {
+        self.result = result as u8;
+        self
+    }
+
+    pub fn with_bytes(mut self, bytes: usize) -> Self {
+        self.bytes = bytes as u64;
+        self
+    }
+
     pub fn as_ptr(&self) -> *const Self {
         self as *const Self
     }
@@ -34,3 +135,105 @@ impl Event {
         array
     }
 }
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// A tracing event at debug level, emitted next to a probe when the tracing feature is on.
+// The spans around them come from tracing::instrument, see InMemoryStore::get_entry.
+#[cfg(feature = "tracing")]
+macro_rules! trace_event {
+    ($($arg:tt)*) => {
+        tracing::debug!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_event {
+    ($($arg:tt)*) => {};
+}
+
+pub(crate) use trace_event;
+
+// Trace id of one operation, shared by all of its events. The UUID is generated by the first event
+// which is actually traced, so an untraced operation never pays for it. That event also starts
+// the clock of Event::duration_ns.
+#[derive(Default)]
+pub struct TraceId(OnceCell<(Uuid, Instant)>);
+
+impl TraceId {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        self.get().0.as_bytes()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.get().1.elapsed()
+    }
+
+    fn get(&self) -> &(Uuid, Instant) {
+        self.0.get_or_init(|| (Uuid::new_v4(), Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c_str(field: &[c_char]) -> String {
+        field
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as u8 as char)
+            .collect()
+    }
+
+    #[test]
+    fn test_event_layout() {
+        // the offsets the bundled bpftrace scripts read the fields at
+        assert_eq!(std::mem::size_of::<Event>(), 104 + KEY_CAPACITY);
+        assert_eq!(std::mem::align_of::<Event>(), 8);
+
+        let event = Event::new("get", "end", "some-key");
+        let base = event.as_ptr() as usize;
+        assert_eq!(&event.key_len as *const _ as usize - base, 4);
+        assert_eq!(&event.monotonic_ns as *const _ as usize - base, 8);
+        assert_eq!(&event.key_hash as *const _ as usize - base, 32);
+        assert_eq!(&event.trace_id as *const _ as usize - base, 40);
+        assert_eq!(&event.method as *const _ as usize - base, 56);
+        assert_eq!(&event.event as *const _ as usize - base, 88);
+        assert_eq!(&event.key as *const _ as usize - base, 104);
+    }
+
+    #[test]
+    fn test_event() {
+        let trace_id = TraceId::new();
+        let event = Event::new("get", "end", "some-key")
+            .with_trace_id(&trace_id)
+            .with_result(EventResult::New)
+            .with_bytes(42);
+
+        assert_eq!(event.version, EVENT_VERSION);
+        assert_eq!(c_str(&event.method), "get");
+        assert_eq!(c_str(&event.event), "end");
+        assert_eq!(event.key_len, 8);
+        assert_eq!(&event.key[..8], b"some-key");
+        assert_eq!(event.key_hash, fnv1a(b"some-key"));
+        assert_eq!(&event.trace_id, trace_id.as_bytes());
+        assert_eq!(event.result, EventResult::New as u8);
+        assert_eq!(event.bytes, 42);
+
+        // a long key is cut, its length and hash are of the whole key
+        let key = "k".repeat(KEY_CAPACITY + 10);
+        let event = Event::new("get", "start", &key);
+        assert_eq!(event.key_len as usize, KEY_CAPACITY + 10);
+        assert_eq!(event.key_hash, fnv1a(key.as_bytes()));
+        assert_eq!(event.trace_id, [0; 16]);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::trace;
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use probe::probe;
+use uuid::Uuid;
+
+const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+// keys are hashed into slots, an invalidation dirties every key of its slot
+const SLOTS: usize = 4096;
+// read timeout of the subscription, also how often a heartbeat is sent through it
+const HEARTBEAT: Duration = Duration::from_secs(1);
+
+// Redis client side caching in broadcasting mode: Redis publishes every modified key under the tracked
+// prefixes to a subscribed connection, a background thread bumps the sequence number of the key's slot.
+// Keys outside the prefixes are never clean, they always take the etag check.
+// An entry remembers the sequence number of its slot from before the request which validated it,
+// it's clean as long as the number didn't move and the subscription is healthy.
+// Any disconnect or missed heartbeat bumps every slot, so entries fall back to the etag check.
+// Like any client side caching, a write is seen locally only once its invalidation is delivered.
+pub(crate) struct Tracker {
+    healthy: AtomicBool,
+    stopped: AtomicBool,
+    seqs: Box<[AtomicU64]>,
+    hasher: RandomState,
+    prefixes: Vec<String>,
+}
+
+impl Tracker {
+    pub fn start(client: redis::Client, prefixes: &[&str]) -> io::Result<Arc<Self>> {
+        let tracker = Arc::new(Self::new(prefixes));
+
+        let listener = tracker.clone();
+        thread::Builder::new()
+            .name("ccache-tracking".to_string())
+            .spawn(move || listener.run(client))?;
+
+        Ok(tracker)
+    }
+
+    fn new(prefixes: &[&str]) -> Self {
+        Self {
+            healthy: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            // 0 is left for entries which were never tracked
+            seqs: (0..SLOTS).map(|_| AtomicU64::new(1)).collect(),
+            hasher: RandomState::new(),
+            prefixes: prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+        }
+    }
+
+    // Sequence number of key, read it before sending the request which validates key.
+    pub fn seq(&self, key: &str) -> u64 {
+        self.slot(key.as_bytes()).load(Ordering::SeqCst)
+    }
+
+    // Whether nothing invalidated key since seq was read.
+    pub fn is_clean(&self, key: &str, seq: u64) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+            && self.seq(key) == seq
+            && self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    fn slot(&self, key: &[u8]) -> &AtomicU64 {
+        &self.seqs[self.hasher.hash_one(key) as usize % SLOTS]
+    }
+
+    fn invalidate(&self, key: &[u8]) {
+        self.slot(key).fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn invalidate_all(&self) {
+        for seq in self.seqs.iter() {
+            seq.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn run(&self, client: redis::Client) {
+        while !self.stopped.load(Ordering::SeqCst) {
+            if let Err(e) = self.listen(&client) {
+                self.healthy.store(false, Ordering::SeqCst);
+
+                probe!(
+                    ccache,
+                    store,
+                    trace::Event::new("tracking", "broken", &e.to_string()).as_ptr()
+                );
+
+                thread::sleep(HEARTBEAT);
+            }
+        }
+
+        self.healthy.store(false, Ordering::SeqCst);
+    }
+
+    // Subscribes and handles invalidations, returns Ok once stopped.
+    fn listen(&self, client: &redis::Client) -> Result<(), redis::RedisError> {
+        let heartbeat_channel = format!("ccache:tracking:{}", Uuid::new_v4());
+
+        let mut pubsub_conn = client.get_connection()?;
+        let id: i64 = redis::cmd("CLIENT").arg("ID").query(&mut pubsub_conn)?;
+        pubsub_conn.set_read_timeout(Some(HEARTBEAT))?;
+        let mut pubsub = pubsub_conn.as_pubsub();
+        pubsub.subscribe(INVALIDATE_CHANNEL)?;
+        pubsub.subscribe(&heartbeat_channel)?;
+
+        // tracking is bound to this connection, invalidations go to the subscribed one.
+        // Without a prefix BCAST would publish every write of the database.
+        let mut tracking_conn = client.get_connection()?;
+        let mut tracking = redis::cmd("CLIENT");
+        tracking
+            .arg("TRACKING")
+            .arg("ON")
+            .arg("REDIRECT")
+            .arg(id)
+            .arg("BCAST");
+        for prefix in &self.prefixes {
+            tracking.arg("PREFIX").arg(prefix);
+        }
+        tracking.query::<()>(&mut tracking_conn)?;
+
+        // entries validated before now may have missed invalidations
+        self.invalidate_all();
+        self.healthy.store(true, Ordering::SeqCst);
+
+        probe!(
+            ccache,
+            store,
+            trace::Event::new("tracking", "connected", "").as_ptr()
+        );
+
+        // None sends the first heartbeat right away
+        let mut last_sent: Option<Instant> = None;
+        let mut last_received = Instant::now();
+
+        while !self.stopped.load(Ordering::SeqCst) {
+            match pubsub.get_message() {
+                Ok(msg) if msg.get_channel_name() == heartbeat_channel => {
+                    last_received = Instant::now();
+                }
+                // the payload is the modified keys, or nil when the whole database was flushed
+                Ok(msg) => match msg.get_payload::<Option<Vec<Vec<u8>>>>() {
+                    Ok(Some(keys)) => keys.iter().for_each(|key| self.invalidate(key)),
+                    _ => self.invalidate_all(),
+                },
+                Err(e) if e.is_timeout() => {}
+                Err(e) => return Err(e),
+            }
+
+            // a heartbeat makes a round trip through the subscription, so a silently dropped
+            // subscription is found out too
+            if last_received.elapsed() > HEARTBEAT * 3 {
+                return Err(redis::RedisError::from((
+                    redis::ErrorKind::IoError,
+                    "tracking heartbeat timed out",
+                )));
+            }
+            if last_sent.is_none_or(|sent| sent.elapsed() >= HEARTBEAT) {
+                redis::cmd("PUBLISH")
+                    .arg(&heartbeat_channel)
+                    .arg("")
+                    .query::<i64>(&mut tracking_conn)?;
+                last_sent = Some(Instant::now());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalidate() {
+        let tracker = Tracker::new(&["key-"]);
+        tracker.healthy.store(true, Ordering::SeqCst);
+
+        let seq = tracker.seq("key-0");
+        assert!(tracker.is_clean("key-0", seq));
+        assert!(!tracker.is_clean("key-0", 0));
+
+        tracker.invalidate(b"key-0");
+        assert!(!tracker.is_clean("key-0", seq));
+        let seq = tracker.seq("key-0");
+        assert!(tracker.is_clean("key-0", seq));
+
+        tracker.invalidate_all();
+        assert!(!tracker.is_clean("key-0", seq));
+        let seq = tracker.seq("key-0");
+
+        tracker.healthy.store(false, Ordering::SeqCst);
+        assert!(!tracker.is_clean("key-0", seq));
+    }
+
+    #[test]
+    fn test_untracked_prefix() {
+        let tracker = Tracker::new(&["key-"]);
+        tracker.healthy.store(true, Ordering::SeqCst);
+
+        let seq = tracker.seq("other-0");
+        assert!(!tracker.is_clean("other-0", seq));
+    }
+}
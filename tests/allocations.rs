@@ -0,0 +1,149 @@
+// Allocations of the unchanged get path. The counting allocator is global, so it gets a test binary of its own.
+
+use ccache::errors::{DecodeError, EncodeError};
+use ccache::in_memory_store::{GetResult, InMemoryStore};
+use ccache::serializable::Serializable;
+
+use bincode::{Decode, Encode};
+use derive::Serializable;
+use flate2::Compression;
+use redis::Value;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::time::Duration;
+
+// counts the allocations of each thread
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+#[derive(Encode, Decode, Serializable, PartialEq, Debug, Clone)]
+struct Entity {
+    x: f32,
+    y: f32,
+}
+
+// A Redis which replies with prepared values, in order. Parsing a real reply allocates in redis-rs,
+// this keeps the count to what ccache does.
+struct Replies(VecDeque<Value>);
+
+impl Replies {
+    fn new() -> Self {
+        // the reply of INSERT_TO_REDIS_SCRIPT
+        let inserted = Value::Bulk(vec![
+            Value::Int(0),
+            Value::Data(b"1700000000000001".to_vec()),
+        ]);
+
+        Replies(VecDeque::from([inserted]))
+    }
+
+    // the reply of GET_FROM_REDIS_SCRIPT to a get whose etag is unchanged
+    fn unchanged(&mut self, lease_ms: &str, n: usize) {
+        for _ in 0..n {
+            self.0.push_back(Value::Bulk(vec![
+                Value::Data(b"etag".to_vec()),
+                Value::Data(b"-1".to_vec()),
+                Value::Data(b"lease_ms".to_vec()),
+                Value::Data(lease_ms.as_bytes().to_vec()),
+            ]));
+        }
+    }
+}
+
+impl redis::ConnectionLike for Replies {
+    fn req_packed_command(&mut self, _cmd: &[u8]) -> redis::RedisResult<Value> {
+        Ok(self.0.pop_front().expect("no reply left"))
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        _cmd: &[u8],
+        _offset: usize,
+        _count: usize,
+    ) -> redis::RedisResult<Vec<Value>> {
+        Err(redis::RedisError::from((
+            redis::ErrorKind::ClientError,
+            "pipelines not supported",
+        )))
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+
+    fn check_connection(&mut self) -> bool {
+        true
+    }
+
+    fn is_open(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn test_unchanged_get_does_not_allocate() {
+    let store = InMemoryStore::<Entity>::new();
+    let mut conn = Replies::new();
+    store
+        .insert("key-0", Entity { x: 1.0, y: 0.0 }, &mut conn)
+        .unwrap();
+
+    // the first get grows the buffers it reuses
+    conn.unchanged("0", 101);
+    store.get("key-0", &mut conn).unwrap();
+
+    let before = allocations();
+    for _ in 0..100 {
+        assert!(matches!(
+            store.get("key-0", &mut conn),
+            Ok(GetResult::Unchanged(_))
+        ));
+    }
+    assert_eq!(allocations(), before);
+    assert!(conn.0.is_empty());
+}
+
+#[test]
+fn test_leased_get_does_not_allocate() {
+    let store = InMemoryStore::<Entity>::new().with_read_lease(Duration::from_secs(60));
+    let mut conn = Replies::new();
+    store
+        .insert("key-0", Entity { x: 1.0, y: 0.0 }, &mut conn)
+        .unwrap();
+
+    // granted a lease, later gets are served locally
+    conn.unchanged("60000", 1);
+    store.get("key-0", &mut conn).unwrap();
+
+    let before = allocations();
+    for _ in 0..100 {
+        assert!(matches!(
+            store.get("key-0", &mut conn),
+            Ok(GetResult::Unchanged(_))
+        ));
+    }
+    assert_eq!(allocations(), before);
+}